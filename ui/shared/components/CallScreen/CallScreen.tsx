@@ -14,6 +14,7 @@ interface CallScreenProps {
   chatMessages: ChatMessageType[];
   myRole: Role | null;
   isMuted: boolean;
+  canUnmute?: boolean; // False while the host controls unmuting and hasn't let us
   onToggleMute: () => void;
   onSendMessage: (message: string) => void;
   onUpdateRole: (targetId: string, newRole: Role) => void;
@@ -35,6 +36,7 @@ export const CallScreen: React.FC<CallScreenProps> = ({
   chatMessages,
   myRole,
   isMuted,
+  canUnmute = true,
   onToggleMute,
   onSendMessage,
   onUpdateRole,
@@ -256,7 +258,7 @@ export const CallScreen: React.FC<CallScreenProps> = ({
       </div>
 
       <div className="call-controls">
-        {(myRole === 'Speaker' || myRole === 'Admin') && (
+        {(myRole === 'Speaker' || myRole === 'Admin') && (canUnmute || !isMuted) && (
           <button onClick={onToggleMute} className={`mute-button ${isMuted ? 'muted' : ''}`}>
             {isMuted ? '🔇 Unmute' : '🎤 Mute'}
           </button>
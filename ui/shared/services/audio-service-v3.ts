@@ -402,6 +402,7 @@ export class AudioServiceV3 {
 
   // Jitter buffers for incoming audio
   private jitterBuffers: Map<string, JitterBuffer> = new Map();
+  private streamEpoch: number | undefined; // Server bumps this when output sequences restart from 0
 
   // Audio playback
   private playbackContext: AudioContext | null = null;
@@ -734,6 +735,17 @@ export class AudioServiceV3 {
     // This handles role changes where stream ID changes from "mix-for-X" to "server-mix"
     const bufferKey = 'unified-mix';
     let jitterBuffer = this.jitterBuffers.get(bufferKey);
+
+    // A new epoch means sequence numbers started over, so start over with a fresh buffer
+    if (audioData.streamEpoch !== undefined && audioData.streamEpoch !== this.streamEpoch) {
+      this.streamEpoch = audioData.streamEpoch;
+      if (jitterBuffer) {
+        jitterBuffer.cleanup();
+        this.jitterBuffers.delete(bufferKey);
+        jitterBuffer = undefined;
+      }
+    }
+
     if (!jitterBuffer) {
       jitterBuffer = new JitterBuffer(this.playbackContext || undefined);
       this.jitterBuffers.set(bufferKey, jitterBuffer);
@@ -19,6 +19,8 @@ export interface BaseVoiceState {
   myParticipantId: string | null;
   myRole: Role | null;
   callEnded: boolean;
+  hostControlsMute: boolean; // Only moderators and those they've let unmute may unmute
+  canUnmute: boolean;
 
   // Audio state
   localStream: MediaStream | null;
@@ -78,6 +80,8 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
   audioLevels: new Map(),
   speakingStates: new Map(),
   callEnded: false,
+  hostControlsMute: false,
+  canUnmute: true,
   mySettings: settingsFromWire(DEFAULT_SETTINGS),
 
   // Actions
@@ -204,6 +208,9 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
 
     if (audioService) {
       const newMutedState = !isMuted;
+      if (!newMutedState && !get().canUnmute) {
+        return;
+      }
 
       // Update store state FIRST
       set({ isMuted: newMutedState });
@@ -280,6 +287,15 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
 
   handleWebSocketMessage: (message: any) => {
 
+    // Answer the server's keepalive ping, or it will eventually drop us
+    if (message === 'Ping') {
+      const ws = get().wsConnection;
+      if (ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify({ Pong: null }));
+      }
+      return;
+    }
+
     if (message.ParticipantJoined) {
       const participant = message.ParticipantJoined.participant;
       set((state: BaseVoiceState) => ({
@@ -364,7 +380,7 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
 
     // Special handling for ui-call JoinSuccess message
     if (message.JoinSuccess) {
-      const { participantId, role, participants, chatHistory, authToken, hostId } = message.JoinSuccess;
+      const { participantId, role, participants, chatHistory, authToken, hostId, hostControlsMute } = message.JoinSuccess;
       const participantsMap = new Map();
       participants.forEach((p: ParticipantInfo) => {
         participantsMap.set(p.id, p);
@@ -388,6 +404,8 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
         participants: participantsMap,
         chatMessages: chatHistory || [],
         hostId: hostId || null,
+        hostControlsMute: !!hostControlsMute,
+        canUnmute: !hostControlsMute || role === 'Admin' || !!myParticipant?.unmuteGranted,
         isMuted: true, // Everyone starts muted
         isAuthenticated: true, // Mark as authenticated after JoinSuccess
         mySettings: myParticipant?.settings ? 
@@ -428,6 +446,28 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
         }
         return state;
       });
+
+      // The server muted us (deafening, a moderator, echo): stop sending too
+      if (participantId === get().myParticipantId && isMuted && !get().isMuted) {
+        set({ isMuted: true });
+        get().audioService?.toggleMute(true)
+          .catch((error: Error) => console.error('[VoiceStore] Failed to mute after server mute:', error));
+      }
+    }
+
+    // Handle the host taking or giving back control of unmuting
+    if (message.MutePolicyUpdated) {
+      const { hostControlsMute } = message.MutePolicyUpdated;
+      const me = get().participants.get(get().myParticipantId);
+      set({
+        hostControlsMute,
+        canUnmute: !hostControlsMute || get().myRole === 'Admin' || !!me?.unmuteGranted
+      });
+    }
+
+    // A moderator lifted our mute; we stay muted until we unmute ourselves
+    if (message === 'UnmuteAllowed' || message.UnmuteAllowed) {
+      set({ canUnmute: true });
     }
 
     // Handle speaking state updates from VAD
@@ -554,9 +594,13 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
     // Show call ended screen for the user who clicked Leave
     set({ callEnded: true });
 
-    // FIRST: Close WebSocket connection immediately to prevent race conditions
+    // Leave over the same socket so the server handles it before the close that follows,
+    // rather than treating the close as a dropped connection
     const ws = state.wsConnection;
     if (ws) {
+      if (ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify({ Leave: null }));
+      }
       ws.close();
       // Clear the connection state immediately
       set({ wsConnection: null, connectionStatus: 'disconnected', isAuthenticated: false });
@@ -565,19 +609,6 @@ export const createBaseVoiceStore = (set: any, get: any): BaseVoiceStore => ({
     // Clean up audio immediately to stop any ongoing streams
     get().cleanupAudio();
 
-    try {
-      // Import the API function
-      const { leaveCall: leaveCallApi } = await import('../../../target/ui/caller-utils');
-      
-      // Call the leave API
-      await leaveCallApi({
-        callId: state.currentCall.id,
-        participantId: state.myParticipantId
-      });
-      
-    } catch (error) {
-    }
-
     // Clean up remaining state but keep callEnded: true
     set({
       currentCall: null,
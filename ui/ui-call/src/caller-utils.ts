@@ -93,11 +93,6 @@ export interface JoinInfo {
   authToken: string | null;
 }
 
-export interface LeaveCallReq {
-  callId: string;
-  participantId: string;
-}
-
 export interface NodeHandshakeReq {
   callId: string;
 }
@@ -142,10 +137,6 @@ export interface JoinCallUnauthenticatedRequest {
   JoinCallUnauthenticated: [string, JoinCallReq]
 }
 
-export interface LeaveCallRequest {
-  LeaveCall: LeaveCallReq
-}
-
 export interface NodeHandshakeRequest {
   NodeHandshake: NodeHandshakeReq
 }
@@ -162,8 +153,6 @@ export type JoinCallResponse = { Ok: JoinInfo } | { Err: string };
 
 export type JoinCallUnauthenticatedResponse = { Ok: JoinInfo } | { Err: string };
 
-export type LeaveCallResponse = { Ok: void } | { Err: string };
-
 export type NodeHandshakeResponse = { Ok: NodeHandshakeResp } | { Err: string };
 
 export type UpdateRoleResponse = { Ok: void } | { Err: string };
@@ -223,19 +212,6 @@ export async function joinCallUnauthenticated(callId: string, request: JoinCallR
   return await apiRequest<JoinCallUnauthenticatedRequest, JoinInfo>('joinCallUnauthenticated', 'POST', data);
 }
 
-/**
- * leaveCall
- * @param request: LeaveCallReq * @returns Promise with result
- * @throws ApiError if the request fails
- */
-export async function leaveCall(request: LeaveCallReq): Promise<void> {
-  const data: LeaveCallRequest = {
-    LeaveCall: request,
-  };
-
-  return await apiRequest<LeaveCallRequest, void>('leaveCall', 'POST', data);
-}
-
 /**
  * nodeHandshake
  * @param request: NodeHandshakeReq * @returns Promise with result
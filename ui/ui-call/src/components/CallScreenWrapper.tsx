@@ -28,6 +28,7 @@ export const CallScreenWrapper: React.FC = () => {
     myRole,
     myParticipantId,
     isMuted,
+    canUnmute,
     toggleMute,
     sendChatMessage,
     updateRole,
@@ -72,6 +73,7 @@ export const CallScreenWrapper: React.FC = () => {
         myRole={myRole}
         myParticipantId={myParticipantId || ''}
         isMuted={isMuted}
+        canUnmute={canUnmute}
         onToggleMute={toggleMute}
         onSendMessage={sendChatMessage}
         onUpdateRole={updateRole}
@@ -23,4 +23,7 @@ export interface ChatMessage {
   senderName: string;
   content: string;
   timestamp: number;
-}
\ No newline at end of file
+  kind: ChatMessageKind;
+}
+
+export type ChatMessageKind = 'User' | 'System' | 'Announcement' | 'Join' | 'Leave';
\ No newline at end of file
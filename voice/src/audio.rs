@@ -1,30 +1,342 @@
+use crate::codec::{CodecFactory, LibOpus, OpusDecode, OpusEncode};
+use crate::ogg::OggDemuxer;
+use crate::transcription::{Caption, TranscriptionSink};
 use hyperware_app_common::hyperware_process_lib::println;
-use opus::{Application, Channels, Decoder, Encoder};
-use std::collections::HashMap;
+use opus::Repacketizer;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-const SAMPLE_RATE: u32 = 48000;
-const FRAME_SIZE: usize = 960; // 20ms at 48kHz
+pub const SAMPLE_RATE: u32 = 48000;
+pub const FRAME_SIZE: usize = 960; // 20ms at 48kHz
 const OPUS_BITRATE: i32 = 32000;
+const MIN_OPUS_BITRATE: i32 = 6000;
+const MAX_OPUS_BITRATE: i32 = 510000;
+const ENVELOPE_RAMP_FRAMES: f32 = 3.0; // Frames to fade fully in or out (~60ms)
+const ENVELOPE_SILENCE_THRESHOLD: f32 = 0.001; // Peak below which a frame counts as silent
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000]; // Rates libopus can decode at
+const MAX_STREAMS_PER_PARTICIPANT: usize = 4;
+const ECHO_HISTORY_FRAMES: usize = 10; // How far back (~200ms) to look for a participant's own mix
+const ECHO_DECIMATION: usize = 8; // Correlate at 6kHz to keep the lag search cheap
+const ECHO_CORRELATION_THRESHOLD: f32 = 0.9;
+const ECHO_MIN_PEAK: f32 = 0.05; // Quieter input can't be judged reliably
+const ECHO_DETECTION_FRAMES: u32 = 50; // ~1s of sustained correlation before flagging
+const COMPRESSION_THRESHOLD: f32 = 0.7; // Peaks above this are squashed...
+const COMPRESSION_RATIO: f32 = 4.0; // ...by this much
+const Q15_ONE: f32 = 32768.0; // 1.0 in the fixed-point mix's Q15 gains
+const DECODER_RESET_FAILURES: u32 = 10; // Consecutive decode errors before a decoder is presumed stuck
+const DEFAULT_OUTPUT_GAIN: f32 = 0.9; // About 1dB of headroom below full scale
+const MAX_CONCEALED_FRAMES: u32 = 5; // Longer gaps are a speaker pausing or reconnecting, not loss
+const CONCEALED_FRAME_MARKER: &[u8] = &[0]; // Stands in for the bytes a concealed frame never had
+const CORRUPT_FRAME_FALLBACK_GAIN: f32 = 0.5; // Start of the fade-out over a corrupt frame's stand-in
+const LIMITER_CEILING: f32 = 0.9; // Input peaks above this (~-1dBFS) are pulled down to it at once...
+const LIMITER_RECOVERY_FRAMES: f32 = 25.0; // ...and gain climbs back to unity over at most ~500ms
+pub const DEFAULT_STREAM: &str = "mic"; // Used when a client doesn't tag its audio
+pub const DEFAULT_NODE_DECODER_LIMIT: usize = 1000;
+
+// Opus decoders alive across every call's processor, and how many there may be. Node-wide, since
+// it's the node's memory and CPU that many busy rooms would exhaust together.
+static LIVE_DECODERS: AtomicUsize = AtomicUsize::new(0);
+static NODE_DECODER_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_NODE_DECODER_LIMIT);
+
+/// Cap the Opus decoders all calls on this node may hold between them. Existing decoders are kept
+/// if it's lowered; only new speakers are refused until enough go away.
+pub fn set_node_decoder_limit(limit: usize) {
+    NODE_DECODER_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+pub fn live_decoders() -> usize {
+    LIVE_DECODERS.load(Ordering::Relaxed)
+}
+
+fn decoder_budget_available() -> bool {
+    live_decoders() < NODE_DECODER_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Keeps LIVE_DECODERS in step however a decoder is dropped.
+struct CountedDecoder(Box<dyn OpusDecode>);
+
+impl OpusDecode for CountedDecoder {
+    fn decode(&mut self, packet: &[u8], output: &mut [i16]) -> Result<usize, String> {
+        self.0.decode(packet, output)
+    }
+}
+
+impl Drop for CountedDecoder {
+    fn drop(&mut self) {
+        LIVE_DECODERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// One participant may send several concurrent streams (e.g. mic and system audio), so everything on
+// the input side is keyed by (participant_id, stream)
+type StreamKey = (String, String);
+
+fn stream_key(participant_id: &str, stream: &str) -> StreamKey {
+    (participant_id.to_string(), stream.to_string())
+}
+
+/// How audio travels between clients and the mixer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    #[default]
+    Opus,
+    /// Raw 16-bit little-endian PCM, for LAN/same-node calls where CPU matters more than bandwidth.
+    /// Input is mono; output follows the listener's channel count, interleaved when stereo. No Opus
+    /// encoder or decoder is ever created.
+    Pcm,
+}
+
+/// What the mixer hears in place of a speaker's frames that never arrived. Any mode but Silence
+/// also covers a frame that arrives but won't decode: the first in a run becomes a faded copy of the
+/// last good frame and any after it silence, rather than the frame being dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LossMode {
+    #[default]
+    Silence,
+    /// Repeat the last good frame, but only once so longer gaps don't turn robotic.
+    Hold,
+    /// Opus packet loss concealment. PCM calls have no decoder to ask, so they hold instead.
+    Plc,
+}
+
+/// How hard each speaker's input is cleaned up before it's mixed: a high-pass filter against rumble
+/// and hum, plus a gate that pulls quiet frames (room noise between words) down towards a floor.
+/// Every level but Off costs one filter pass and one RMS per frame, a handful of multiplies per
+/// sample and small next to decoding; the higher levels cut more, not work harder. Off suits music.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoiseSuppression {
+    Off,
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+impl NoiseSuppression {
+    /// (high-pass cutoff in Hz, gate threshold as frame RMS, gain applied to gated frames)
+    fn parameters(self) -> Option<(f32, f32, f32)> {
+        match self {
+            NoiseSuppression::Off => None,
+            NoiseSuppression::Low => Some((80.0, 0.005, 0.3)),
+            NoiseSuppression::Medium => Some((120.0, 0.01, 0.1)),
+            NoiseSuppression::High => Some((200.0, 0.02, 0.0)),
+        }
+    }
+}
+
+/// How each listener's mix is put together. Independent of `AudioCodec`, which only decides how
+/// the result travels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MixStrategy {
+    /// Everyone but the listener themselves.
+    #[default]
+    MixMinus,
+    /// Only the `count` loudest streams each tick, plus any pinned speakers, so crowded calls stay
+    /// intelligible.
+    TopN { count: usize },
+}
+
+/// Per-call mixer settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AudioConfig {
+    /// Scale each mix by 1/sqrt(N) for N contributors so loudness stays roughly constant as more
+    /// people talk. Off by default since it changes relative levels.
+    pub normalize_mix_gain: bool,
+    /// Accept Ogg Opus streams (e.g. from MediaRecorder) as well as raw Opus frames.
+    pub accept_ogg_input: bool,
+    /// Transcribe speakers and broadcast captions. Only speaking segments are detected for now.
+    pub captions: bool,
+    /// Give a participant fresh encoder/decoder state whenever a role change grants or takes away
+    /// speaking, so no state from before carries over. On by default.
+    pub reset_codecs_on_role_change: bool,
+    pub codec: AudioCodec,
+    pub loss_mode: LossMode,
+    /// Scale applied to each finished mix just before it's converted to 16-bit, after compression,
+    /// leaving headroom so residual and inter-sample peaks don't clip on playback. Clamped to 0..=1;
+    /// 0.9 by default.
+    pub output_gain: f32,
+    /// Mix mono targets at the mixer rate with integer arithmetic (an i32 accumulator over 16-bit
+    /// samples) instead of floats, for hosts where the float round-trip is measurably expensive.
+    /// Stereo and resampled targets always use the float path.
+    pub fixed_point_mix: bool,
+    pub mix_strategy: MixStrategy,
+    /// Low by default, which suits speech.
+    pub noise_suppression: NoiseSuppression,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            normalize_mix_gain: false,
+            accept_ogg_input: false,
+            captions: false,
+            reset_codecs_on_role_change: true,
+            codec: AudioCodec::Opus,
+            loss_mode: LossMode::Silence,
+            output_gain: DEFAULT_OUTPUT_GAIN,
+            fixed_point_mix: false,
+            mix_strategy: MixStrategy::MixMinus,
+            noise_suppression: NoiseSuppression::Low,
+        }
+    }
+}
 
 pub struct AudioProcessor {
-    // Opus encoder/decoder for each participant
-    decoders: HashMap<String, Decoder>,
-    encoders: HashMap<String, Encoder>, // Per-participant encoders for better quality
+    config: AudioConfig,
+
+    // Opus decoder for each input stream, encoder for each participant
+    codecs: Box<dyn CodecFactory>,
+    decoders: HashMap<StreamKey, Box<dyn OpusDecode>>,
+    encoders: HashMap<String, Box<dyn OpusEncode>>, // Per-participant encoders for better quality
 
     // Mixing state
-    participant_audio_raw: HashMap<String, Vec<u8>>, // Store raw Opus data
-    participant_audio: HashMap<String, Vec<f32>>,    // Decoded audio for mixing
+    participant_audio_raw: HashMap<StreamKey, Vec<u8>>, // Store raw Opus data
+    participant_audio: HashMap<StreamKey, Vec<f32>>,    // Decoded audio for mixing
     participant_has_sent_audio: HashMap<String, bool>, // Track if participant has ever sent audio
     participant_last_audio_time: HashMap<String, std::time::Instant>, // Track last audio time
     master_mix: Vec<f32>,
 
     // Voice activity detection per participant
     vad_detectors: HashMap<String, VoiceActivityDetector>,
+
+    // Spots participants re-capturing their own mix (speakers without echo cancellation)
+    echo_detectors: HashMap<String, EchoDetector>,
+    echo_suspects: Vec<String>,
+
+    // Fade-in/fade-out state applied to each stream's contribution
+    participant_envelopes: HashMap<StreamKey, Envelope>,
+
+    // Catch sudden loud input (a dropped headset, feedback) before it reaches anyone's mix
+    limiters: HashMap<StreamKey, PeakLimiter>,
+
+    // Filter and gate state per stream; see `NoiseSuppression`
+    noise_suppressors: HashMap<StreamKey, NoiseSuppressor>,
+
+    // Push-to-talk: when set, only this participant's audio is mixed
+    exclusive_speaker: Option<String>,
+
+    // Always mixed at full gain, regardless of push-to-talk, envelopes or normalization
+    pinned: HashSet<String>,
+
+    // How each participant's mix-minus output is encoded
+    output_profiles: HashMap<String, OutputProfile>,
+
+    // Clients capturing below SAMPLE_RATE are decoded at their own rate and resampled up
+    input_sample_rates: HashMap<StreamKey, u32>,
+    resamplers: HashMap<StreamKey, Resampler>,
+
+    // Listeners who asked for a lower output rate get their mix downsampled before encoding, with
+    // one resampler per output channel
+    output_resamplers: HashMap<String, Vec<Resampler>>,
+
+    // Only populated for streams sending Ogg when the call accepts it
+    ogg_demuxers: HashMap<StreamKey, OggDemuxer>,
+
+    // Last input sequence number seen on each stream, to spot lost frames
+    last_input_sequences: HashMap<StreamKey, u32>,
+
+    // Consecutive decode errors per stream; see DECODER_RESET_FAILURES
+    decode_failures: HashMap<StreamKey, u32>,
+
+    // Sees every decoded frame that goes into the mix
+    transcription: Option<Box<dyn TranscriptionSink>>,
+
+    stats: AudioStats,
+}
+
+/// Running counters for a call's audio pipeline.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioStats {
+    pub frames_decoded: u64,
+    pub decoder_resets: u64, // Decoders recreated after getting stuck on errors
+    pub lock_failures: u64, // Poisoned-lock recoveries; see `lock_processor` in lib.rs
+}
+
+/// What this server's mixer accepts and produces, so clients can set up their encoders before
+/// joining. A call may narrow these: its codec and Ogg setting come from its `AudioConfig` and are
+/// repeated in JoinSuccess.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioCapabilities {
+    pub codecs: Vec<AudioCodec>,
+    pub default_codec: AudioCodec,
+    pub input_sample_rates: Vec<u32>, // Each is resampled to `mixer_sample_rate`
+    pub output_sample_rates: Vec<u32>,
+    pub mixer_sample_rate: u32,
+    pub frame_samples: usize, // Per channel at `mixer_sample_rate`
+    pub input_channels: u32,  // Inputs are decoded as mono
+    pub stereo_output: bool,
+    pub min_bitrate: i32,
+    pub max_bitrate: i32,
+    pub default_bitrate: i32,
+    pub max_streams_per_participant: usize,
+    pub ogg_input_by_default: bool, // Raw Opus frames are always accepted
+}
+
+impl AudioCapabilities {
+    pub fn current() -> Self {
+        let defaults = AudioConfig::default();
+        Self {
+            codecs: vec![AudioCodec::Opus, AudioCodec::Pcm],
+            default_codec: defaults.codec,
+            input_sample_rates: OPUS_SAMPLE_RATES.to_vec(),
+            output_sample_rates: OPUS_SAMPLE_RATES.to_vec(),
+            mixer_sample_rate: SAMPLE_RATE,
+            frame_samples: FRAME_SIZE,
+            input_channels: 1,
+            stereo_output: true,
+            min_bitrate: MIN_OPUS_BITRATE,
+            max_bitrate: MAX_OPUS_BITRATE,
+            default_bitrate: OPUS_BITRATE,
+            max_streams_per_participant: MAX_STREAMS_PER_PARTICIPANT,
+            ogg_input_by_default: defaults.accept_ogg_input,
+        }
+    }
+}
+
+/// Per-target encoder settings derived from what that participant can decode.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputProfile {
+    pub bitrate: i32,
+    pub stereo: bool, // Otherwise the stereo mix is downmixed to mono before encoding
+    pub sample_rate: u32,
+}
+
+impl OutputProfile {
+    pub fn new(max_bitrate: Option<i32>, bitrate_ceiling: Option<i32>, stereo: bool, sample_rate: Option<u32>) -> Self {
+        let bitrate = max_bitrate.unwrap_or(OPUS_BITRATE);
+        let bitrate = bitrate_ceiling.map_or(bitrate, |ceiling| bitrate.min(ceiling));
+        Self {
+            bitrate: bitrate.clamp(MIN_OPUS_BITRATE, MAX_OPUS_BITRATE),
+            stereo,
+            sample_rate: sample_rate.unwrap_or(SAMPLE_RATE),
+        }
+    }
+}
+
+impl Default for OutputProfile {
+    fn default() -> Self {
+        Self::new(None, None, false, None)
+    }
 }
 
 impl AudioProcessor {
-    pub fn new() -> Self {
+    pub fn new(config: AudioConfig) -> Self {
+        Self::with_codecs(config, Box::new(LibOpus))
+    }
+
+    /// A processor whose encoders and decoders come from `codecs` rather than libopus.
+    pub fn with_codecs(config: AudioConfig, codecs: Box<dyn CodecFactory>) -> Self {
         Self {
+            config,
+            codecs,
             decoders: HashMap::new(),
             encoders: HashMap::new(),
             participant_audio_raw: HashMap::new(),
@@ -33,69 +345,336 @@ impl AudioProcessor {
             participant_last_audio_time: HashMap::new(),
             master_mix: vec![0.0; FRAME_SIZE],
             vad_detectors: HashMap::new(),
+            echo_detectors: HashMap::new(),
+            echo_suspects: Vec::new(),
+            participant_envelopes: HashMap::new(),
+            limiters: HashMap::new(),
+            noise_suppressors: HashMap::new(),
+            exclusive_speaker: None,
+            pinned: HashSet::new(),
+            output_profiles: HashMap::new(),
+            input_sample_rates: HashMap::new(),
+            resamplers: HashMap::new(),
+            output_resamplers: HashMap::new(),
+            ogg_demuxers: HashMap::new(),
+            last_input_sequences: HashMap::new(),
+            decode_failures: HashMap::new(),
+            transcription: None,
+            stats: AudioStats::default(),
         }
     }
 
+    pub fn stats(&self) -> AudioStats {
+        self.stats
+    }
+
+    pub fn record_lock_failure(&mut self) {
+        self.stats.lock_failures += 1;
+    }
+
+    /// Plug in a transcriber, replacing any current one; `None` turns captions off.
+    pub fn set_transcription_sink(&mut self, sink: Option<Box<dyn TranscriptionSink>>) {
+        self.transcription = sink;
+    }
+
+    /// Participants whose input has been tracking their own mix, since the last call.
+    pub fn take_echo_suspects(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.echo_suspects)
+    }
+
+    /// Captions the transcriber has produced since the last call.
+    pub fn take_captions(&mut self) -> Vec<Caption> {
+        self.transcription
+            .as_mut()
+            .map_or_else(Vec::new, |sink| sink.drain_captions())
+    }
+
     pub fn has_participant(&self, participant_id: &str) -> bool {
-        self.participant_audio_raw.contains_key(participant_id)
+        self.output_profiles.contains_key(participant_id)
     }
 
     pub fn add_participant(&mut self, participant_id: String) -> Result<(), String> {
-        // Create Opus decoder for this participant
-        match Decoder::new(SAMPLE_RATE, Channels::Mono) {
-            Ok(decoder) => {
-                self.decoders.insert(participant_id.clone(), decoder);
-            }
-            Err(e) => {
-                return Err(format!("Failed to create Opus decoder: {}", e));
-            }
-        }
-
         // Create Opus encoder for this participant's mix-minus output
-        match Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip) {
-            Ok(mut encoder) => {
-                // Set bitrate for better quality
-                if let Err(e) = encoder.set_bitrate(opus::Bitrate::Bits(OPUS_BITRATE)) {
-                    println!("Failed to set Opus bitrate: {}", e);
+        if self.config.codec == AudioCodec::Opus {
+            match self.codecs.encoder(SAMPLE_RATE, false) {
+                Ok(mut encoder) => {
+                    // Set bitrate for better quality
+                    if let Err(e) = encoder.set_bitrate(OPUS_BITRATE) {
+                        println!("Failed to set Opus bitrate: {}", e);
+                    }
+                    self.encoders.insert(participant_id.clone(), encoder);
+                }
+                Err(e) => {
+                    return Err(format!("Failed to create Opus encoder: {}", e));
                 }
-                self.encoders.insert(participant_id.clone(), encoder);
-            }
-            Err(e) => {
-                return Err(format!("Failed to create Opus encoder: {}", e));
             }
         }
 
-        self.participant_audio_raw
-            .insert(participant_id.clone(), Vec::new());
-        self.participant_audio
-            .insert(participant_id.clone(), vec![0.0; FRAME_SIZE]);
+        self.add_stream(&participant_id, DEFAULT_STREAM)?;
         self.participant_has_sent_audio
             .insert(participant_id.clone(), false);
         self.participant_last_audio_time
             .insert(participant_id.clone(), std::time::Instant::now());
         self.vad_detectors
             .insert(participant_id.clone(), VoiceActivityDetector::new());
+        self.echo_detectors
+            .insert(participant_id.clone(), EchoDetector::default());
+        self.output_profiles
+            .insert(participant_id.clone(), OutputProfile::default());
+
+        Ok(())
+    }
+
+    /// A decoder for `key`, counted against the node-wide limit unless it replaces one it already has.
+    fn new_decoder(&self, key: &StreamKey, sample_rate: u32) -> Result<Box<dyn OpusDecode>, String> {
+        if !self.decoders.contains_key(key) && !decoder_budget_available() {
+            return Err("This node is handling as many speakers as it can, so you can listen but not speak right now".to_string());
+        }
+        let decoder = self
+            .codecs
+            .decoder(sample_rate)
+            .map_err(|e| format!("Failed to create Opus decoder: {}", e))?;
+        LIVE_DECODERS.fetch_add(1, Ordering::Relaxed);
+        Ok(Box::new(CountedDecoder(decoder)))
+    }
+
+    /// Set up decoding and mixing state for one of a participant's input streams.
+    fn add_stream(&mut self, participant_id: &str, stream: &str) -> Result<(), String> {
+        let stream_count = self
+            .participant_audio
+            .keys()
+            .filter(|(id, _)| id == participant_id)
+            .count();
+        if stream_count >= MAX_STREAMS_PER_PARTICIPANT {
+            return Err(format!(
+                "At most {} audio streams per participant",
+                MAX_STREAMS_PER_PARTICIPANT
+            ));
+        }
+
+        let key = stream_key(participant_id, stream);
+        // Over the node's budget a joiner can still listen; they're only refused once they speak
+        if self.config.codec == AudioCodec::Opus && decoder_budget_available() {
+            let decoder = self.new_decoder(&key, SAMPLE_RATE)?;
+            self.decoders.insert(key.clone(), decoder);
+        }
+        self.participant_audio_raw.insert(key.clone(), Vec::new());
+        self.participant_audio
+            .insert(key.clone(), vec![0.0; FRAME_SIZE]);
+        self.participant_envelopes.insert(key.clone(), Envelope::new());
+        self.input_sample_rates.insert(key, SAMPLE_RATE);
+        Ok(())
+    }
+
+    /// Switch the rate one of a participant's streams is decoded at, setting the stream up if it's
+    /// new. Anything other than `SAMPLE_RATE` is resampled to it after decoding so the mixer only
+    /// ever sees one rate.
+    pub fn set_input_sample_rate(&mut self, participant_id: &str, stream: &str, sample_rate: u32) -> Result<(), String> {
+        if !is_supported_sample_rate(sample_rate) {
+            return Err(format!("Unsupported sample rate: {}", sample_rate));
+        }
+        let key = stream_key(participant_id, stream);
+        if !self.participant_audio.contains_key(&key) {
+            self.add_stream(participant_id, stream)?;
+        }
+        if self.input_sample_rates.get(&key) == Some(&sample_rate) && self.input_ready(&key) {
+            return Ok(());
+        }
+
+        if self.config.codec == AudioCodec::Opus {
+            let decoder = self.new_decoder(&key, sample_rate)?;
+            self.decoders.insert(key.clone(), decoder);
+        }
+        self.input_sample_rates.insert(key.clone(), sample_rate);
+        if sample_rate == SAMPLE_RATE {
+            self.resamplers.remove(&key);
+        } else {
+            self.resamplers
+                .insert(key, Resampler::new(sample_rate, SAMPLE_RATE));
+        }
+        println!(
+            "AudioProcessor: Decoding {} ({}) at {} Hz",
+            participant_id, stream, sample_rate
+        );
+        Ok(())
+    }
+
+    /// Whether a stream can take audio at its current input rate, i.e. hasn't had its decoding side
+    /// released.
+    fn input_ready(&self, key: &StreamKey) -> bool {
+        match self.config.codec {
+            AudioCodec::Opus => self.decoders.contains_key(key),
+            AudioCodec::Pcm => {
+                self.input_sample_rates.get(key) == Some(&SAMPLE_RATE) || self.resamplers.contains_key(key)
+            }
+        }
+    }
+
+    pub fn set_output_profile(&mut self, participant_id: &str, profile: OutputProfile) -> Result<(), String> {
+        if !is_supported_sample_rate(profile.sample_rate) {
+            return Err(format!("Unsupported sample rate: {}", profile.sample_rate));
+        }
+
+        // An encoder's rate and channel count are fixed at creation, so changing either needs a
+        // new encoder
+        let current = self
+            .output_profiles
+            .get(participant_id)
+            .copied()
+            .unwrap_or_default();
+        if current.sample_rate != profile.sample_rate || current.stereo != profile.stereo {
+            if self.config.codec == AudioCodec::Opus {
+                let encoder = self
+                    .codecs
+                    .encoder(profile.sample_rate, profile.stereo)
+                    .map_err(|e| format!("Failed to create Opus encoder: {}", e))?;
+                self.encoders.insert(participant_id.to_string(), encoder);
+            }
+            if profile.sample_rate == SAMPLE_RATE {
+                self.output_resamplers.remove(participant_id);
+            } else {
+                let channel_count = if profile.stereo { 2 } else { 1 };
+                self.output_resamplers.insert(
+                    participant_id.to_string(),
+                    (0..channel_count)
+                        .map(|_| Resampler::new(SAMPLE_RATE, profile.sample_rate))
+                        .collect(),
+                );
+            }
+        }
 
+        if self.config.codec == AudioCodec::Opus {
+            let encoder = self
+                .encoders
+                .get_mut(participant_id)
+                .ok_or_else(|| format!("No encoder found for participant {}", participant_id))?;
+            encoder
+                .set_bitrate(profile.bitrate)
+                .map_err(|e| format!("Failed to set Opus bitrate: {}", e))?;
+        }
+        println!(
+            "AudioProcessor: Output for {} at {} bps, {} Hz (stereo capable: {})",
+            participant_id, profile.bitrate, profile.sample_rate, profile.stereo
+        );
+        self.output_profiles
+            .insert(participant_id.to_string(), profile);
         Ok(())
     }
 
+    pub fn set_exclusive_speaker(&mut self, participant_id: Option<String>) {
+        self.exclusive_speaker = participant_id;
+    }
+
+    /// Participants who haven't sent audio for at least `timeout` (counting from when they were
+    /// added or last marked active).
+    pub fn idle_participants(&self, timeout: std::time::Duration) -> Vec<String> {
+        self.participant_last_audio_time
+            .iter()
+            .filter(|(_, last_time)| last_time.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Restart a participant's idle clock, e.g. when they are newly allowed to speak.
+    pub fn mark_active(&mut self, participant_id: &str) {
+        if let Some(last_time) = self.participant_last_audio_time.get_mut(participant_id) {
+            *last_time = std::time::Instant::now();
+        }
+    }
+
+    /// Free the decoding side for someone who can no longer speak. They keep their encoder so they
+    /// still hear the mix; `set_input_sample_rate` recreates the decoder if they speak again.
+    pub fn release_decoder(&mut self, participant_id: &str) {
+        self.decoders.retain(|(id, _), _| id != participant_id);
+        self.resamplers.retain(|(id, _), _| id != participant_id);
+        self.decode_failures.retain(|(id, _), _| id != participant_id);
+        for ((id, _), raw_audio) in self.participant_audio_raw.iter_mut() {
+            if id == participant_id {
+                raw_audio.clear();
+            }
+        }
+    }
+
+    /// Start a participant over with fresh codec state, keeping their output profile and pin. Extra
+    /// streams and any non-default input rate are set up again by their next frame.
+    pub fn reset_codecs(&mut self, participant_id: &str) -> Result<(), String> {
+        let Some(profile) = self.output_profiles.get(participant_id).copied() else {
+            return Ok(());
+        };
+        let pinned = self.pinned.contains(participant_id);
+        self.remove_participant(participant_id);
+        self.add_participant(participant_id.to_string())?;
+        self.set_pinned(participant_id, pinned);
+        self.set_output_profile(participant_id, profile)
+    }
+
+    pub fn set_pinned(&mut self, participant_id: &str, pinned: bool) {
+        if pinned {
+            self.pinned.insert(participant_id.to_string());
+        } else {
+            self.pinned.remove(participant_id);
+        }
+    }
+
     pub fn remove_participant(&mut self, participant_id: &str) {
-        self.decoders.remove(participant_id);
+        if let Some(sink) = self.transcription.as_mut() {
+            sink.end_participant(participant_id);
+        }
+        self.pinned.remove(participant_id);
+        self.decoders.retain(|(id, _), _| id != participant_id);
         self.encoders.remove(participant_id);
-        self.participant_audio_raw.remove(participant_id);
-        self.participant_audio.remove(participant_id);
+        self.participant_audio_raw.retain(|(id, _), _| id != participant_id);
+        self.participant_audio.retain(|(id, _), _| id != participant_id);
         self.participant_has_sent_audio.remove(participant_id);
         self.participant_last_audio_time.remove(participant_id);
         self.vad_detectors.remove(participant_id);
+        self.echo_detectors.remove(participant_id);
+        self.participant_envelopes.retain(|(id, _), _| id != participant_id);
+        self.limiters.retain(|(id, _), _| id != participant_id);
+        self.noise_suppressors.retain(|(id, _), _| id != participant_id);
+        self.output_profiles.remove(participant_id);
+        self.input_sample_rates.retain(|(id, _), _| id != participant_id);
+        self.resamplers.retain(|(id, _), _| id != participant_id);
+        self.output_resamplers.remove(participant_id);
+        self.ogg_demuxers.retain(|(id, _), _| id != participant_id);
+        self.last_input_sequences.retain(|(id, _), _| id != participant_id);
+        self.decode_failures.retain(|(id, _), _| id != participant_id);
+    }
+
+    /// Split an incoming message into Opus packets. Raw frames are a single packet; an Ogg stream
+    /// chunk may hold several, or none if it ends mid-page.
+    pub fn extract_packets(&mut self, participant_id: &str, stream: &str, data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let key = stream_key(participant_id, stream);
+        if self.config.codec == AudioCodec::Pcm {
+            return Ok(vec![data.to_vec()]);
+        }
+        let mid_page = self
+            .ogg_demuxers
+            .get(&key)
+            .is_some_and(|demuxer| demuxer.is_mid_page());
+        if !data.starts_with(b"OggS") && !mid_page {
+            return Ok(vec![data.to_vec()]);
+        }
+        if !self.config.accept_ogg_input {
+            return Err("Ogg container not supported - expected raw Opus frames".to_string());
+        }
+
+        self.ogg_demuxers
+            .entry(key)
+            .or_default()
+            .push(data)
     }
 
     pub fn decode_audio(
         &mut self,
         participant_id: &str,
+        stream: &str,
         opus_data: &[u8],
     ) -> Result<Vec<f32>, String> {
+        let key = stream_key(participant_id, stream);
+
         // Check if we received Ogg-wrapped data instead of raw Opus
-        if opus_data.len() >= 4 && &opus_data[0..4] == b"OggS" {
+        if self.config.codec == AudioCodec::Opus && opus_data.len() >= 4 && &opus_data[0..4] == b"OggS" {
             println!(
                 "ERROR: Received Ogg-wrapped data from {}, expected raw Opus frames!",
                 participant_id
@@ -126,7 +705,7 @@ impl AudioProcessor {
         }
 
         // Store the original data as-is for later mix-minus processing
-        if let Some(raw_audio) = self.participant_audio_raw.get_mut(participant_id) {
+        if let Some(raw_audio) = self.participant_audio_raw.get_mut(&key) {
             *raw_audio = opus_data.to_vec();
         }
 
@@ -134,13 +713,22 @@ impl AudioProcessor {
 
         // Raw data already stored above
 
+        if self.config.codec == AudioCodec::Pcm {
+            return self.decode_pcm(&key, opus_data)
+                .map(|frame| self.clean_input(&key, frame));
+        }
+
         // Decode using the participant's decoder
-        if let Some(decoder) = self.decoders.get_mut(participant_id) {
-            // Prepare output buffer for decoded samples
-            let mut output = vec![0i16; FRAME_SIZE];
+        if let Some(decoder) = self.decoders.get_mut(&key) {
+            // Prepare output buffer for decoded samples, sized for 20ms at the input rate
+            let input_rate = self.input_sample_rates.get(&key).copied().unwrap_or(SAMPLE_RATE);
+            let mut output = vec![0i16; FRAME_SIZE * input_rate as usize / SAMPLE_RATE as usize];
 
-            match decoder.decode(opus_data, &mut output, false) {
+            match decoder.decode(opus_data, &mut output) {
                 Ok(samples_decoded) => {
+                    self.stats.frames_decoded += 1;
+                    self.decode_failures.remove(&key);
+
                     // Convert i16 samples to f32
                     let mut float_output = Vec::with_capacity(samples_decoded);
                     let mut max_sample = 0.0f32;
@@ -155,20 +743,34 @@ impl AudioProcessor {
                         samples_decoded, participant_id, max_sample
                     );
 
-                    // Pad with zeros if needed
+                    if let Some(resampler) = self.resamplers.get_mut(&key) {
+                        float_output = resampler.process(&float_output);
+                    }
+
+                    // Fit to exactly one mixer frame; resampling can be off by a sample
+                    float_output.truncate(FRAME_SIZE);
                     while float_output.len() < FRAME_SIZE {
                         float_output.push(0.0);
                     }
 
-                    Ok(float_output)
+                    Ok(self.clean_input(&key, float_output))
                 }
                 Err(e) => {
                     println!(
                         "Opus decode error for participant {}: {}",
                         participant_id, e
                     );
-                    // Return error instead of silence to avoid hiding issues
-                    Err(format!("Opus decode failed: {}", e))
+                    let failures = self.decode_failures.entry(key.clone()).or_insert(0);
+                    *failures += 1;
+                    let failures = *failures;
+                    if failures >= DECODER_RESET_FAILURES {
+                        self.reset_decoder(&key, input_rate);
+                    }
+                    if self.config.loss_mode == LossMode::Silence {
+                        // Return error instead of silence to avoid hiding issues
+                        return Err(format!("Opus decode failed: {}", e));
+                    }
+                    Ok(self.corrupt_frame_fallback(&key, failures == 1))
                 }
             }
         } else {
@@ -183,9 +785,141 @@ impl AudioProcessor {
         }
     }
 
-    pub fn update_participant_audio(&mut self, participant_id: &str, audio: Vec<f32>) {
+    /// Stand in for a frame that failed to decode. The stream's buffer still holds its last good
+    /// (already cleaned) frame; the first failure in a run gets that back faded out from
+    /// CORRUPT_FRAME_FALLBACK_GAIN, so a lone bad packet is a soft dip instead of a hole. Later ones
+    /// get silence, so a stream that stays corrupt doesn't stutter.
+    fn corrupt_frame_fallback(&self, key: &StreamKey, first_failure: bool) -> Vec<f32> {
+        let last_good = self.participant_audio.get(key).filter(|_| first_failure);
+        let Some(last_good) = last_good else {
+            return vec![0.0; FRAME_SIZE];
+        };
+        let len = last_good.len().max(1) as f32;
+        last_good
+            .iter()
+            .enumerate()
+            .map(|(i, &sample)| sample * CORRUPT_FRAME_FALLBACK_GAIN * (1.0 - i as f32 / len))
+            .collect()
+    }
+
+    /// Replace a decoder that keeps failing with a fresh one at the same rate. Its resampler restarts
+    /// too, since whatever it was holding came from the bad state.
+    fn reset_decoder(&mut self, key: &StreamKey, input_rate: u32) {
+        self.decode_failures.remove(key);
+        match self.new_decoder(key, input_rate) {
+            Ok(decoder) => {
+                self.decoders.insert(key.clone(), decoder);
+                if input_rate != SAMPLE_RATE {
+                    self.resamplers
+                        .insert(key.clone(), Resampler::new(input_rate, SAMPLE_RATE));
+                }
+                self.stats.decoder_resets += 1;
+                println!(
+                    "AudioProcessor: Reset decoder for {} ({}) after {} consecutive errors",
+                    key.0, key.1, DECODER_RESET_FAILURES
+                );
+            }
+            Err(e) => println!("Failed to reset decoder for {}: {}", key.0, e),
+        }
+    }
+
+    fn clean_input(&mut self, key: &StreamKey, mut frame: Vec<f32>) -> Vec<f32> {
+        if let Some((cutoff_hz, gate_threshold, gate_floor)) = self.config.noise_suppression.parameters() {
+            self.noise_suppressors
+                .entry(key.clone())
+                .or_default()
+                .apply(&mut frame, cutoff_hz, gate_threshold, gate_floor);
+        }
+        self.limiters.entry(key.clone()).or_default().apply(&mut frame);
+        frame
+    }
+
+    /// PCM passthrough: raw 16-bit little-endian samples at the stream's input rate.
+    fn decode_pcm(&mut self, key: &StreamKey, data: &[u8]) -> Result<Vec<f32>, String> {
+        let chunks = data.chunks_exact(2);
+        if !chunks.remainder().is_empty() {
+            return Err("PCM frame has an odd number of bytes".to_string());
+        }
+        self.stats.frames_decoded += 1;
+
+        let mut samples: Vec<f32> = chunks
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+            .collect();
+        if let Some(resampler) = self.resamplers.get_mut(key) {
+            samples = resampler.process(&samples);
+        }
+        samples.resize(FRAME_SIZE, 0.0);
+        Ok(samples)
+    }
+
+    /// Record a stream's input sequence number and return how many frames went missing just before
+    /// it. Reordered frames, restarted counters and gaps too long to be loss all count as none.
+    pub fn missing_frames(&mut self, participant_id: &str, stream: &str, sequence: u32) -> u32 {
+        let previous = self
+            .last_input_sequences
+            .insert(stream_key(participant_id, stream), sequence);
+        match previous {
+            Some(previous) => {
+                let gap = sequence.wrapping_sub(previous).wrapping_sub(1);
+                if gap <= MAX_CONCEALED_FRAMES { gap } else { 0 }
+            }
+            None => 0,
+        }
+    }
+
+    /// Stand in for the `index`th frame of a gap according to the call's `LossMode`, leaving it as
+    /// the stream's pending audio for the next mix just as a decoded frame would be.
+    pub fn conceal_frame(&mut self, participant_id: &str, stream: &str, index: u32) -> Result<(), String> {
+        let key = stream_key(participant_id, stream);
+        let hold = match self.config.loss_mode {
+            LossMode::Silence => false,
+            LossMode::Hold => true,
+            LossMode::Plc => self.config.codec == AudioCodec::Pcm,
+        };
+
+        let frame = if hold {
+            if index == 0 {
+                // The stream's buffer still holds its last good frame
+                None
+            } else {
+                Some(vec![0.0; FRAME_SIZE])
+            }
+        } else if self.config.loss_mode == LossMode::Plc {
+            let decoder = self
+                .decoders
+                .get_mut(&key)
+                .ok_or_else(|| format!("No decoder found for participant {}", participant_id))?;
+            let input_rate = self.input_sample_rates.get(&key).copied().unwrap_or(SAMPLE_RATE);
+            let mut output = vec![0i16; FRAME_SIZE * input_rate as usize / SAMPLE_RATE as usize];
+            // An empty packet asks libopus to extrapolate from its state
+            let samples_decoded = decoder
+                .decode(&[], &mut output)
+                .map_err(|e| format!("Opus PLC failed: {}", e))?;
+            let mut samples: Vec<f32> = output[..samples_decoded]
+                .iter()
+                .map(|&sample| sample as f32 / 32768.0)
+                .collect();
+            if let Some(resampler) = self.resamplers.get_mut(&key) {
+                samples = resampler.process(&samples);
+            }
+            samples.resize(FRAME_SIZE, 0.0);
+            Some(samples)
+        } else {
+            Some(vec![0.0; FRAME_SIZE])
+        };
+
+        if let Some(frame) = frame {
+            self.update_participant_audio(participant_id, stream, frame);
+        }
+        if let Some(raw_audio) = self.participant_audio_raw.get_mut(&key) {
+            *raw_audio = CONCEALED_FRAME_MARKER.to_vec();
+        }
+        Ok(())
+    }
+
+    pub fn update_participant_audio(&mut self, participant_id: &str, stream: &str, audio: Vec<f32>) {
         // For now, just update the buffer
-        if let Some(buffer) = self.participant_audio.get_mut(participant_id) {
+        if let Some(buffer) = self.participant_audio.get_mut(&stream_key(participant_id, stream)) {
             // Copy audio data, ensuring we don't exceed buffer size
             let copy_len = audio.len().min(buffer.len());
             buffer[..copy_len].copy_from_slice(&audio[..copy_len]);
@@ -196,19 +930,36 @@ impl AudioProcessor {
         let mut outputs = HashMap::new();
 
         // Get all registered participants (not just those with recent audio)
-        let all_participants: Vec<String> = self.participant_audio.keys().cloned().collect();
+        let mut all_participants: Vec<String> = self.output_profiles.keys().cloned().collect();
+        all_participants.sort();
 
-        // Get participants who currently have audio data to contribute to the mix
-        let active_participants: Vec<(String, Vec<u8>, Vec<f32>)> = self
+        // Get streams that currently have audio data to contribute to the mix
+        let mut active_participants: Vec<(StreamKey, Vec<u8>, Vec<f32>)> = self
             .participant_audio_raw
             .iter()
             .filter(|(_, data)| !data.is_empty())
-            .filter_map(|(id, raw_data)| {
+            .filter(|((id, _), _)| match &self.exclusive_speaker {
+                Some(speaker) => speaker == id || self.pinned.contains(id),
+                None => true,
+            })
+            .filter_map(|(key, raw_data)| {
                 self.participant_audio
-                    .get(id)
-                    .map(|decoded_audio| (id.clone(), raw_data.clone(), decoded_audio.clone()))
+                    .get(key)
+                    .map(|decoded_audio| (key.clone(), raw_data.clone(), decoded_audio.clone()))
             })
             .collect();
+        // HashMap order varies between runs; summing in a fixed order keeps float rounding, and so the
+        // mixes, identical for identical input
+        active_participants.sort_by(|(a_key, _, _), (b_key, _, _)| a_key.cmp(b_key));
+
+        // Everything collected is consumed by this tick, whether or not it ends up mixed. Clearing
+        // before TopN narrows the list keeps a stream it passes over from coming back later with a
+        // stale frame.
+        for (key, _, _) in &active_participants {
+            if let Some(raw_audio) = self.participant_audio_raw.get_mut(key) {
+                raw_audio.clear();
+            }
+        }
 
         println!(
             "AudioProcessor: Creating mixes for {} participants, {} have active audio",
@@ -216,6 +967,48 @@ impl AudioProcessor {
             active_participants.len()
         );
 
+        // Transcribe what people actually said, before envelopes and mixing gain reshape it
+        if let Some(sink) = self.transcription.as_mut() {
+            for ((participant_id, _), _, decoded_audio) in &active_participants {
+                sink.push_frame(participant_id, decoded_audio);
+            }
+        }
+
+        // Look for each speaker's recent mix in what they just sent
+        for ((participant_id, _), _, decoded_audio) in &active_participants {
+            let Some(detector) = self.echo_detectors.get_mut(participant_id) else { continue };
+            if detector.check_input(decoded_audio) && !self.echo_suspects.contains(participant_id) {
+                println!("AudioProcessor: {} appears to be feeding back the call", participant_id);
+                self.echo_suspects.push(participant_id.clone());
+            }
+        }
+
+        match self.config.mix_strategy {
+            MixStrategy::MixMinus => {}
+            MixStrategy::TopN { count } => {
+                active_participants = select_loudest(active_participants, count, &self.pinned);
+            }
+        }
+
+        // Ramp each contribution in on onset and out as it goes silent to avoid clicks
+        for (key, _, decoded_audio) in active_participants.iter_mut() {
+            if self.pinned.contains(&key.0) {
+                continue;
+            }
+            let peak = decoded_audio.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
+            let target = if peak < ENVELOPE_SILENCE_THRESHOLD { 0.0 } else { 1.0 };
+            if let Some(envelope) = self.participant_envelopes.get_mut(key) {
+                envelope.apply(decoded_audio, target);
+            }
+        }
+
+        // Streams that sent nothing this tick fade towards zero so their next onset ramps in
+        for (key, envelope) in self.participant_envelopes.iter_mut() {
+            if !active_participants.iter().any(|(active_key, _, _)| active_key == key) {
+                envelope.decay();
+            }
+        }
+
         if active_participants.is_empty() {
             // No audio data to process
             println!("AudioProcessor: No active audio data to process");
@@ -223,6 +1016,23 @@ impl AudioProcessor {
         }
 
         // Skip creating a shared listener mix - we'll create individual mixes for each participant
+        let output_gain = self.config.output_gain.clamp(0.0, 1.0);
+
+        // The fixed-point path quantizes each contribution once per tick and shares it across targets
+        let quantized: HashMap<StreamKey, Vec<i16>> = if self.config.fixed_point_mix {
+            active_participants
+                .iter()
+                .map(|(key, _, decoded_audio)| {
+                    let samples = decoded_audio
+                        .iter()
+                        .map(|&sample| (sample.clamp(-1.0, 1.0) * 32767.0) as i16)
+                        .collect();
+                    (key.clone(), samples)
+                })
+                .collect()
+        } else {
+            HashMap::new()
+        };
 
         // Create personalized mix for each registered participant
         for target_id in &all_participants {
@@ -232,51 +1042,98 @@ impl AudioProcessor {
             }
 
             // Check if this participant has sent audio (i.e., is an active speaker)
-            let is_active_speaker = active_participants.iter().any(|(id, _, _)| id == target_id);
+            let is_active_speaker = active_participants.iter().any(|((id, _), _, _)| id == target_id);
+
+            let contributors = mix_contributors(&active_participants, target_id);
 
-            let mut mix = vec![0.0f32; FRAME_SIZE];
-            let mut has_audio = false;
+            if !contributors.is_empty() {
+                // Keep loudness steady as more people talk. This applies before compression so the
+                // compressor only has to catch genuine peaks rather than the sum of many voices.
+                // Pinned speakers are exempt and always come through at full gain.
+                let normalized_gain = if self.config.normalize_mix_gain && contributors.len() > 1 {
+                    1.0 / (contributors.len() as f32).sqrt()
+                } else {
+                    1.0
+                };
+                let gains: Vec<f32> = contributors
+                    .iter()
+                    .map(|((participant_id, _), _, _)| {
+                        if self.pinned.contains(participant_id) { 1.0 } else { normalized_gain }
+                    })
+                    .collect();
 
-            if is_active_speaker {
-                // For active speakers: create mix-minus (exclude their own audio)
-                for (participant_id, _, decoded_audio) in &active_participants {
-                    if participant_id != target_id {
-                        has_audio = true;
+                let stereo = self.output_profiles.get(target_id).is_some_and(|profile| profile.stereo);
+                let i16_buffer = if self.config.fixed_point_mix
+                    && !stereo
+                    && !self.output_resamplers.contains_key(target_id)
+                {
+                    let frames: Vec<&[i16]> = contributors
+                        .iter()
+                        .filter_map(|(key, _, _)| quantized.get(key).map(Vec::as_slice))
+                        .collect();
+                    let mix = mix_fixed_point(&frames, &gains, output_gain);
+                    if let Some(detector) = self.echo_detectors.get_mut(target_id) {
+                        let mono: Vec<f32> = mix.iter().map(|&sample| sample as f32 / 32768.0).collect();
+                        detector.record_output(&mono);
+                    }
+                    mix
+                } else {
+                    // Mixed as interleaved stereo so stereo-capable targets keep it; mono contributors
+                    // sit in the centre
+                    let mut mix = vec![0.0f32; FRAME_SIZE * 2];
+                    for ((_, _, decoded_audio), &gain) in contributors.iter().zip(&gains) {
                         for i in 0..FRAME_SIZE.min(decoded_audio.len()) {
-                            mix[i] += decoded_audio[i];
+                            let sample = decoded_audio[i] * gain;
+                            mix[2 * i] += sample;
+                            mix[2 * i + 1] += sample;
                         }
                     }
-                }
-            } else {
-                // For listeners/chatters: create full mix (include all audio)
-                for (_, _, decoded_audio) in &active_participants {
-                    has_audio = true;
-                    for i in 0..FRAME_SIZE.min(decoded_audio.len()) {
-                        mix[i] += decoded_audio[i];
+
+                    // Check if mix has actual audio
+                    let max_sample = mix.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
+                    println!(
+                        "AudioProcessor: Mix for {} has max amplitude: {}, is_speaker: {}",
+                        target_id, max_sample, is_active_speaker
+                    );
+
+                    // Apply compression
+                    Self::apply_compression_static(&mut mix);
+
+                    if let Some(detector) = self.echo_detectors.get_mut(target_id) {
+                        detector.record_output(&downmix_to_mono(&mix));
                     }
-                }
-            }
 
-            if has_audio {
-                // Check if mix has actual audio
-                let max_sample = mix.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
-                println!(
-                    "AudioProcessor: Mix for {} has max amplitude: {}, is_speaker: {}",
-                    target_id, max_sample, is_active_speaker
-                );
+                    // Mono-only clients can't play interleaved stereo, so fold it down for them
+                    if !stereo {
+                        mix = downmix_to_mono(&mix);
+                    }
 
-                // Apply compression
-                Self::apply_compression_static(&mut mix);
+                    // Downsample for listeners who asked for a lower rate, keeping exactly one 20ms frame
+                    if let Some(resamplers) = self.output_resamplers.get_mut(target_id) {
+                        mix = resample_interleaved(resamplers, &mix);
+                    }
 
-                // Convert to i16 and encode
-                let i16_buffer: Vec<i16> = mix
-                    .iter()
-                    .map(|&sample| (sample.max(-1.0).min(1.0) * 32767.0) as i16)
-                    .collect();
+                    // Convert to i16 and encode, with the compressor having tamed the peaks and the output
+                    // gain keeping whatever is left clear of full scale
+                    mix
+                        .iter()
+                        .map(|&sample| ((sample * output_gain).max(-1.0).min(1.0) * 32767.0) as i16)
+                        .collect()
+                };
+
+                if self.config.codec == AudioCodec::Pcm {
+                    let pcm: Vec<u8> = i16_buffer.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+                    outputs.insert(target_id.clone(), pcm);
+                    continue;
+                }
 
                 let mut opus_output = vec![0u8; 4000];
                 if let Some(encoder) = self.encoders.get_mut(target_id) {
                     match encoder.encode(&i16_buffer, &mut opus_output) {
+                        Ok(0) => {
+                            // Nothing worth sending (e.g. DTX during silence): no frame this tick
+                            // rather than an empty payload the client would try to decode
+                        }
                         Ok(bytes_written) => {
                             opus_output.truncate(bytes_written);
                             println!(
@@ -297,31 +1154,338 @@ impl AudioProcessor {
             }
         }
 
-        // Clear the raw audio data after creating mixes to avoid reprocessing
-        for (participant_id, _, _) in &active_participants {
-            if let Some(raw_audio) = self.participant_audio_raw.get_mut(participant_id) {
-                raw_audio.clear();
-            }
-        }
-
         outputs
     }
 
     fn apply_compression_static(buffer: &mut [f32]) {
-        const THRESHOLD: f32 = 0.7;
-        const RATIO: f32 = 4.0;
-
         for sample in buffer.iter_mut() {
             let abs_sample = sample.abs();
-            if abs_sample > THRESHOLD {
-                let over = abs_sample - THRESHOLD;
-                let compressed = THRESHOLD + (over / RATIO);
+            if abs_sample > COMPRESSION_THRESHOLD {
+                let over = abs_sample - COMPRESSION_THRESHOLD;
+                let compressed = COMPRESSION_THRESHOLD + (over / COMPRESSION_RATIO);
                 *sample = compressed * sample.signum();
             }
         }
     }
 }
 
+/// Merge consecutive Opus packets into one multi-frame packet. They must share a configuration
+/// (mode, bandwidth, frame size), which holds for back-to-back output of one steady encoder.
+pub fn repacketize(packets: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let mut repacketizer =
+        Repacketizer::new().map_err(|e| format!("Failed to create repacketizer: {}", e))?;
+    let inputs: Vec<&[u8]> = packets.iter().map(|packet| packet.as_slice()).collect();
+
+    // A multi-frame packet adds at most a few bytes of framing per frame
+    let mut output = vec![0u8; packets.iter().map(|packet| packet.len() + 3).sum::<usize>() + 2];
+    let len = repacketizer
+        .combine(&inputs, &mut output)
+        .map_err(|e| format!("Failed to repacketize: {}", e))?;
+    output.truncate(len);
+    Ok(output)
+}
+
+/// The active streams that go into `target_id`'s mix: everyone's but `target_id`'s own. That holds
+/// whether or not the target sent audio this tick, so a speaker pausing between utterances never
+/// hears themselves; listeners/chatters simply have nothing to exclude and get the full mix.
+/// Kept free of processor state so the selection can be checked against hand-built inputs.
+fn mix_contributors<'a>(
+    active: &'a [(StreamKey, Vec<u8>, Vec<f32>)],
+    target_id: &str,
+) -> Vec<&'a (StreamKey, Vec<u8>, Vec<f32>)> {
+    active
+        .iter()
+        .filter(|((participant_id, _), _, _)| participant_id != target_id)
+        .collect()
+}
+
+/// Keep the `count` most energetic streams (ties broken by key so the pick is stable) along with
+/// every pinned speaker's.
+fn select_loudest(
+    active: Vec<(StreamKey, Vec<u8>, Vec<f32>)>,
+    count: usize,
+    pinned: &HashSet<String>,
+) -> Vec<(StreamKey, Vec<u8>, Vec<f32>)> {
+    let (pinned_streams, mut others): (Vec<_>, Vec<_>) = active
+        .into_iter()
+        .partition(|((participant_id, _), _, _)| pinned.contains(participant_id));
+
+    let energy = |samples: &[f32]| samples.iter().map(|s| s * s).sum::<f32>();
+    others.sort_by(|(a_key, _, a), (b_key, _, b)| {
+        energy(b)
+            .total_cmp(&energy(a))
+            .then_with(|| a_key.cmp(b_key))
+    });
+    others.truncate(count);
+
+    let mut selected = pinned_streams;
+    selected.extend(others);
+    selected
+}
+
+/// Integer counterpart of the float mix for a mono target at the mixer rate: the same gains, the same
+/// compression curve and the same output gain, summed in an i32 accumulator and saturated to i16
+/// with no float round-trip per sample.
+fn mix_fixed_point(frames: &[&[i16]], gains: &[f32], output_gain: f32) -> Vec<i16> {
+    let mut mix = vec![0i32; FRAME_SIZE];
+    for (frame, &gain) in frames.iter().zip(gains) {
+        let gain_q15 = (gain * Q15_ONE) as i32;
+        for (acc, &sample) in mix.iter_mut().zip(frame.iter()) {
+            *acc += (sample as i32 * gain_q15) >> 15;
+        }
+    }
+
+    let threshold = (COMPRESSION_THRESHOLD * 32767.0) as i32;
+    let ratio = COMPRESSION_RATIO as i32;
+    let output_gain_q15 = (output_gain * Q15_ONE) as i64;
+    mix.into_iter()
+        .map(|acc| {
+            let magnitude = acc.abs();
+            let compressed = if magnitude > threshold {
+                (threshold + (magnitude - threshold) / ratio) * acc.signum()
+            } else {
+                acc
+            };
+            let scaled = (compressed as i64 * output_gain_q15) >> 15;
+            scaled.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+        })
+        .collect()
+}
+
+/// Average each interleaved left/right pair into a single mono sample.
+fn downmix_to_mono(stereo: &[f32]) -> Vec<f32> {
+    stereo
+        .chunks_exact(2)
+        .map(|pair| (pair[0] + pair[1]) * 0.5)
+        .collect()
+}
+
+/// Resample an interleaved frame with one resampler per channel, keeping exactly one 20ms frame
+/// per channel at the output rate.
+fn resample_interleaved(resamplers: &mut [Resampler], input: &[f32]) -> Vec<f32> {
+    let channel_count = resamplers.len();
+    let Some(output_rate) = resamplers.first().map(|resampler| resampler.output_rate) else {
+        return input.to_vec();
+    };
+    let frame_len = FRAME_SIZE * output_rate as usize / SAMPLE_RATE as usize;
+
+    let mut output = vec![0.0f32; frame_len * channel_count];
+    for (channel, resampler) in resamplers.iter_mut().enumerate() {
+        let samples: Vec<f32> = input.iter().skip(channel).step_by(channel_count).copied().collect();
+        for (i, sample) in resampler.process(&samples).into_iter().take(frame_len).enumerate() {
+            output[i * channel_count + channel] = sample;
+        }
+    }
+    output
+}
+
+/// Whether libopus can encode/decode at this rate.
+pub fn is_supported_sample_rate(sample_rate: u32) -> bool {
+    OPUS_SAMPLE_RATES.contains(&sample_rate)
+}
+
+/// Per-participant gain envelope used to fade contributions in and out of the mix.
+#[derive(Debug, Clone, Copy)]
+struct Envelope {
+    gain: f32,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self { gain: 0.0 }
+    }
+
+    fn step_towards(&self, target: f32) -> f32 {
+        let step = 1.0 / ENVELOPE_RAMP_FRAMES;
+        if target > self.gain {
+            (self.gain + step).min(target)
+        } else {
+            (self.gain - step).max(target)
+        }
+    }
+
+    /// Scale a frame by the envelope, ramping linearly per sample towards `target`.
+    fn apply(&mut self, frame: &mut [f32], target: f32) {
+        let start = self.gain;
+        let end = self.step_towards(target);
+        let len = frame.len().max(1) as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            let gain = start + (end - start) * ((i + 1) as f32 / len);
+            *sample *= gain;
+        }
+        self.gain = end;
+    }
+
+    /// Advance the envelope by one silent frame.
+    fn decay(&mut self) {
+        self.gain = self.step_towards(0.0);
+    }
+}
+
+/// One-pole high-pass filter followed by a soft noise gate. The gate's gain ramps across each frame,
+/// like `Envelope`, so opening and closing don't click.
+#[derive(Debug, Clone, Copy)]
+struct NoiseSuppressor {
+    previous_input: f32,
+    previous_output: f32,
+    gate_gain: f32,
+}
+
+impl Default for NoiseSuppressor {
+    fn default() -> Self {
+        Self { previous_input: 0.0, previous_output: 0.0, gate_gain: 1.0 }
+    }
+}
+
+impl NoiseSuppressor {
+    fn apply(&mut self, frame: &mut [f32], cutoff_hz: f32, gate_threshold: f32, gate_floor: f32) {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+        let alpha = rc / (rc + 1.0 / SAMPLE_RATE as f32);
+        for sample in frame.iter_mut() {
+            let output = alpha * (self.previous_output + *sample - self.previous_input);
+            self.previous_input = *sample;
+            self.previous_output = output;
+            *sample = output;
+        }
+
+        let energy: f32 = frame.iter().map(|sample| sample * sample).sum();
+        let rms = (energy / frame.len().max(1) as f32).sqrt();
+        let target = if rms < gate_threshold { gate_floor } else { 1.0 };
+        let start = self.gate_gain;
+        let len = frame.len().max(1) as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample *= start + (target - start) * ((i + 1) as f32 / len);
+        }
+        self.gate_gain = target;
+    }
+}
+
+/// Fast per-stream peak limiter. A frame whose peak would exceed LIMITER_CEILING has its gain cut
+/// to fit within that same frame, then gain recovers linearly over LIMITER_RECOVERY_FRAMES. It
+/// runs on each decoded input, ahead of the envelopes and the mix's compression.
+#[derive(Debug, Clone, Copy)]
+struct PeakLimiter {
+    gain: f32,
+}
+
+impl Default for PeakLimiter {
+    fn default() -> Self {
+        Self { gain: 1.0 }
+    }
+}
+
+impl PeakLimiter {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let recovered = (self.gain + 1.0 / LIMITER_RECOVERY_FRAMES).min(1.0);
+        self.gain = if peak * recovered > LIMITER_CEILING {
+            // Only ever cut here; a smaller spike after a big one still recovers along the ramp
+            recovered.min(LIMITER_CEILING / peak)
+        } else {
+            recovered
+        };
+        if self.gain < 1.0 {
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+/// Correlates a participant's input against the mixes they were recently sent. Sustained, strong
+/// correlation at any lag within the history means their mic is picking up their speakers.
+#[derive(Debug, Default)]
+struct EchoDetector {
+    history: VecDeque<f32>, // Decimated recent output, oldest first
+    matches: u32,           // Consecutive input frames that matched
+}
+
+impl EchoDetector {
+    fn record_output(&mut self, mix: &[f32]) {
+        self.history.extend(mix.iter().step_by(ECHO_DECIMATION));
+        let capacity = ECHO_HISTORY_FRAMES * FRAME_SIZE / ECHO_DECIMATION;
+        while self.history.len() > capacity {
+            self.history.pop_front();
+        }
+    }
+
+    /// Feed one input frame; true once the input has tracked the output long enough to act on.
+    fn check_input(&mut self, input: &[f32]) -> bool {
+        let input: Vec<f32> = input.iter().step_by(ECHO_DECIMATION).copied().collect();
+        let peak = input.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
+        if peak < ECHO_MIN_PEAK || self.history.len() < input.len() {
+            self.matches = 0;
+            return false;
+        }
+
+        let history = self.history.make_contiguous();
+        let input_energy: f32 = input.iter().map(|s| s * s).sum();
+        let mut best = 0.0f32;
+        for window in history.windows(input.len()) {
+            let window_energy: f32 = window.iter().map(|s| s * s).sum();
+            if window_energy < 1e-6 {
+                continue;
+            }
+            let dot: f32 = window.iter().zip(&input).map(|(a, b)| a * b).sum();
+            best = best.max(dot / (input_energy * window_energy).sqrt());
+        }
+
+        if best < ECHO_CORRELATION_THRESHOLD {
+            self.matches = 0;
+            return false;
+        }
+        self.matches += 1;
+        if self.matches < ECHO_DETECTION_FRAMES {
+            return false;
+        }
+        self.matches = 0;
+        self.history.clear();
+        true
+    }
+}
+
+/// Streaming linear-interpolation resampler. Keeps the last input sample and the fractional read
+/// position between frames so consecutive frames join without a phase discontinuity.
+#[derive(Debug, Clone)]
+struct Resampler {
+    output_rate: u32,
+    step: f64,     // Input samples advanced per output sample
+    position: f64, // Read position into the current frame; index -1 is `previous`
+    previous: f32,
+}
+
+impl Resampler {
+    fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            output_rate,
+            step: input_rate as f64 / output_rate as f64,
+            position: 0.0,
+            previous: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if input.is_empty() {
+            return Vec::new();
+        }
+
+        // Output lags the input by one sample so we always interpolate between known samples
+        let len = input.len() as f64;
+        let mut output = Vec::with_capacity((len / self.step).ceil() as usize);
+        while self.position < len {
+            let index = self.position.floor() as usize;
+            let frac = (self.position - index as f64) as f32;
+            let before = if index == 0 { self.previous } else { input[index - 1] };
+            output.push(before + (input[index] - before) * frac);
+            self.position += self.step;
+        }
+
+        self.position -= len;
+        self.previous = input[input.len() - 1];
+        output
+    }
+}
+
 #[derive(Debug)]
 pub struct VoiceActivityDetector {}
 
@@ -346,3 +1510,242 @@ impl std::fmt::Debug for AudioProcessor {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::PcmPassthrough;
+
+    /// A processor on the passthrough codecs with input cleanup off, so mixes are easy to predict.
+    fn processor(config: AudioConfig) -> AudioProcessor {
+        let config = AudioConfig { noise_suppression: NoiseSuppression::Off, ..config };
+        AudioProcessor::with_codecs(config, Box::new(PcmPassthrough))
+    }
+
+    /// One frame's worth of packet with `sample(i)` at each index.
+    fn packet(sample: impl Fn(usize) -> f32) -> Vec<u8> {
+        (0..FRAME_SIZE)
+            .flat_map(|i| ((sample(i) * 32767.0) as i16).to_le_bytes())
+            .collect()
+    }
+
+    fn samples(packet: &[u8]) -> Vec<i16> {
+        packet
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect()
+    }
+
+    /// Decode a packet and leave it as the stream's audio for the next mix, as the server does.
+    fn speak(processor: &mut AudioProcessor, participant_id: &str, packet: &[u8]) {
+        let frame = processor.decode_audio(participant_id, DEFAULT_STREAM, packet).unwrap();
+        processor.update_participant_audio(participant_id, DEFAULT_STREAM, frame);
+    }
+
+    #[test]
+    fn passthrough_codecs_carry_a_speaker_to_everyone_else() {
+        let mut processor = processor(AudioConfig::default());
+        processor.add_participant("alice".to_string()).unwrap();
+        processor.add_participant("bob".to_string()).unwrap();
+
+        speak(&mut processor, "alice", &packet(|_| 0.25));
+        let outputs = processor.create_mix_minus_outputs();
+
+        assert!(!outputs.contains_key("alice"));
+        let heard = samples(&outputs["bob"]);
+        assert_eq!(heard.len(), FRAME_SIZE);
+        assert!(heard.iter().all(|&sample| sample > 0));
+        assert_eq!(processor.stats().frames_decoded, 1);
+    }
+
+    #[test]
+    fn mono_and_stereo_listeners_share_a_room() {
+        let mut processor = processor(AudioConfig::default());
+        for id in ["alice", "bob", "carol"] {
+            processor.add_participant(id.to_string()).unwrap();
+        }
+        processor.set_output_profile("bob", OutputProfile::new(None, None, true, None)).unwrap();
+
+        speak(&mut processor, "alice", &packet(|i| if i % 2 == 0 { 0.25 } else { -0.25 }));
+        let outputs = processor.create_mix_minus_outputs();
+
+        let stereo = samples(&outputs["bob"]);
+        let mono = samples(&outputs["carol"]);
+        assert_eq!(stereo.len(), FRAME_SIZE * 2);
+        assert_eq!(mono.len(), FRAME_SIZE);
+        // A mono speaker sits in the centre, and downmixing it gives the mono listener the same signal
+        for (pair, &sample) in stereo.chunks_exact(2).zip(&mono) {
+            assert_eq!(pair[0], pair[1]);
+            assert!((pair[0] - sample).abs() <= 1);
+        }
+        assert!(mono.iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn silence_under_dtx_sends_no_frames() {
+        let mut processor = processor(AudioConfig::default());
+        processor.add_participant("alice".to_string()).unwrap();
+        processor.add_participant("bob".to_string()).unwrap();
+
+        // The passthrough encoder writes nothing for a silent frame, as Opus does under DTX
+        speak(&mut processor, "alice", &packet(|_| 0.0));
+        assert!(processor.create_mix_minus_outputs().is_empty());
+
+        speak(&mut processor, "alice", &packet(|_| 0.25));
+        let outputs = processor.create_mix_minus_outputs();
+        assert!(outputs.values().all(|frame| !frame.is_empty()));
+        assert!(outputs.contains_key("bob"));
+    }
+
+    #[test]
+    fn limiter_cuts_a_spike_at_once_and_recovers_slowly() {
+        let peak = |frame: &[f32]| frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let mut limiter = PeakLimiter::default();
+
+        // Loud but ordinary speech, around -3dBFS, passes untouched
+        let mut speech = vec![0.708; FRAME_SIZE];
+        limiter.apply(&mut speech);
+        assert_eq!(speech, vec![0.708; FRAME_SIZE]);
+
+        let mut spike = vec![1.0; FRAME_SIZE];
+        limiter.apply(&mut spike);
+        assert!(peak(&spike) <= LIMITER_CEILING + 1e-6);
+
+        // Quiet input right after is still held down, then climbs back to unity
+        let mut quiet = vec![0.1; FRAME_SIZE];
+        limiter.apply(&mut quiet);
+        assert!(peak(&quiet) < 0.1);
+        for _ in 0..LIMITER_RECOVERY_FRAMES as usize {
+            quiet = vec![0.1; FRAME_SIZE];
+            limiter.apply(&mut quiet);
+        }
+        assert_eq!(peak(&quiet), 0.1);
+    }
+
+    #[test]
+    fn a_speaker_pausing_between_utterances_never_hears_themselves() {
+        let mut processor = processor(AudioConfig::default());
+        processor.add_participant("alice".to_string()).unwrap();
+        processor.add_participant("bob".to_string()).unwrap();
+
+        // Alice only ever fills even samples and Bob odd ones, so any even sample in Alice's mix is her
+        let alice = packet(|i| if i % 2 == 0 { 0.25 } else { 0.0 });
+        let bob = packet(|i| if i % 2 == 1 { 0.25 } else { 0.0 });
+        for alice_speaks in [true, false, false, true, false] {
+            if alice_speaks {
+                speak(&mut processor, "alice", &alice);
+            }
+            speak(&mut processor, "bob", &bob);
+            let outputs = processor.create_mix_minus_outputs();
+
+            let heard = samples(&outputs["alice"]);
+            assert!(heard.iter().step_by(2).all(|&sample| sample == 0));
+            assert!(heard.iter().skip(1).step_by(2).any(|&sample| sample != 0));
+        }
+    }
+
+    #[test]
+    fn a_corrupt_frame_between_good_ones_becomes_a_faded_copy() {
+        let mut processor = processor(AudioConfig { loss_mode: LossMode::Hold, ..AudioConfig::default() });
+        processor.add_participant("alice".to_string()).unwrap();
+        let good = packet(|_| 0.25);
+        let corrupt = [1, 2, 3];
+
+        speak(&mut processor, "alice", &good);
+        let last_good = processor.participant_audio[&stream_key("alice", DEFAULT_STREAM)].clone();
+
+        let stand_in = processor.decode_audio("alice", DEFAULT_STREAM, &corrupt).unwrap();
+        assert_eq!(stand_in[0], last_good[0] * CORRUPT_FRAME_FALLBACK_GAIN);
+        assert!(stand_in.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(stand_in[FRAME_SIZE - 1].abs() < 1e-3);
+
+        // Only the first failure in a run gets a copy; the next is silence
+        let next = processor.decode_audio("alice", DEFAULT_STREAM, &corrupt).unwrap();
+        assert!(next.iter().all(|&sample| sample == 0.0));
+
+        let recovered = processor.decode_audio("alice", DEFAULT_STREAM, &good).unwrap();
+        assert_eq!(recovered, last_good);
+    }
+
+    #[test]
+    fn silence_loss_mode_still_drops_corrupt_frames() {
+        let mut processor = processor(AudioConfig::default());
+        processor.add_participant("alice".to_string()).unwrap();
+        speak(&mut processor, "alice", &packet(|_| 0.25));
+        assert!(processor.decode_audio("alice", DEFAULT_STREAM, &[1, 2, 3]).is_err());
+    }
+
+    /// A hand-built active stream whose every sample is `level`.
+    fn active(participant_id: &str, level: f32) -> (StreamKey, Vec<u8>, Vec<f32>) {
+        (stream_key(participant_id, DEFAULT_STREAM), vec![1], vec![level; FRAME_SIZE])
+    }
+
+    fn contributor_ids(contributors: &[&(StreamKey, Vec<u8>, Vec<f32>)]) -> Vec<String> {
+        contributors.iter().map(|((id, _), _, _)| id.clone()).collect()
+    }
+
+    #[test]
+    fn speakers_never_hear_themselves() {
+        let streams = vec![active("alice", 0.1), active("bob", 0.2), active("carol", 0.3)];
+        for target in ["alice", "bob", "carol"] {
+            let ids = contributor_ids(&mix_contributors(&streams, target));
+            assert_eq!(ids.len(), 2);
+            assert!(!ids.iter().any(|id| id == target));
+        }
+    }
+
+    #[test]
+    fn listeners_hear_every_speaker() {
+        let streams = vec![active("alice", 0.1), active("bob", 0.2)];
+        let ids = contributor_ids(&mix_contributors(&streams, "listener"));
+        assert_eq!(ids, ["alice", "bob"]);
+    }
+
+    #[test]
+    fn top_n_keeps_the_loudest_and_every_pinned_speaker() {
+        let streams = vec![
+            active("alice", 0.1),
+            active("bob", 0.4),
+            active("carol", 0.3),
+            active("dave", 0.2),
+        ];
+        let pinned: HashSet<String> = ["alice".to_string()].into();
+
+        let selected = select_loudest(streams, 2, &pinned);
+        let ids: Vec<&str> = selected.iter().map(|((id, _), _, _)| id.as_str()).collect();
+        assert_eq!(ids, ["alice", "bob", "carol"]);
+
+        // Mix-minus still applies to whatever was selected
+        let ids = contributor_ids(&mix_contributors(&selected, "bob"));
+        assert_eq!(ids, ["alice", "carol"]);
+    }
+
+    #[test]
+    fn a_stream_top_n_passed_over_is_not_mixed_on_a_later_tick() {
+        let mut processor = processor(AudioConfig { mix_strategy: MixStrategy::TopN { count: 1 }, ..AudioConfig::default() });
+        for id in ["alice", "bob", "carol"] {
+            processor.add_participant(id.to_string()).unwrap();
+        }
+        // Alice only ever fills even samples and Bob odd ones, so any odd sample in a mix is Bob
+        let odd_samples_heard = |mix: &[u8]| samples(mix).iter().skip(1).step_by(2).any(|&sample| sample != 0);
+
+        speak(&mut processor, "alice", &packet(|i| if i % 2 == 0 { 0.4 } else { 0.0 }));
+        speak(&mut processor, "bob", &packet(|i| if i % 2 == 1 { 0.1 } else { 0.0 }));
+        let outputs = processor.create_mix_minus_outputs();
+        assert!(!odd_samples_heard(&outputs["carol"]));
+
+        // Bob sends nothing this tick, so his frame from the last one must not fill the free slot
+        speak(&mut processor, "alice", &packet(|i| if i % 2 == 0 { 0.05 } else { 0.0 }));
+        let outputs = processor.create_mix_minus_outputs();
+        assert!(!odd_samples_heard(&outputs["carol"]));
+        assert!(!outputs.contains_key("alice"));
+    }
+
+    #[test]
+    fn a_room_with_nobody_speaking_gets_no_mixes() {
+        let mut processor = processor(AudioConfig::default());
+        processor.add_participant("alice".to_string()).unwrap();
+        processor.add_participant("bob".to_string()).unwrap();
+        assert!(processor.create_mix_minus_outputs().is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+// Seams around libopus. `AudioProcessor` only talks to these traits, so a test build can hand it
+// deterministic fakes (e.g. an identity PCM codec) instead of the native library.
+
+use opus::{Application, Bitrate, Channels, Decoder, Encoder};
+
+pub trait OpusDecode: Send {
+    /// Decode one packet into `output`, returning the samples written. An empty packet asks for
+    /// packet loss concealment.
+    fn decode(&mut self, packet: &[u8], output: &mut [i16]) -> Result<usize, String>;
+}
+
+pub trait OpusEncode: Send {
+    /// Encode one frame, returning the bytes written to `output`.
+    fn encode(&mut self, pcm: &[i16], output: &mut [u8]) -> Result<usize, String>;
+    fn set_bitrate(&mut self, bits_per_second: i32) -> Result<(), String>;
+}
+
+/// Makes the codec instances a processor needs. Decoders are always mono.
+pub trait CodecFactory: Send {
+    fn decoder(&self, sample_rate: u32) -> Result<Box<dyn OpusDecode>, String>;
+    fn encoder(&self, sample_rate: u32, stereo: bool) -> Result<Box<dyn OpusEncode>, String>;
+}
+
+/// The production codecs.
+pub struct LibOpus;
+
+impl CodecFactory for LibOpus {
+    fn decoder(&self, sample_rate: u32) -> Result<Box<dyn OpusDecode>, String> {
+        let decoder = Decoder::new(sample_rate, Channels::Mono).map_err(|e| e.to_string())?;
+        Ok(Box::new(decoder))
+    }
+
+    fn encoder(&self, sample_rate: u32, stereo: bool) -> Result<Box<dyn OpusEncode>, String> {
+        let channels = if stereo { Channels::Stereo } else { Channels::Mono };
+        let encoder =
+            Encoder::new(sample_rate, channels, Application::Voip).map_err(|e| e.to_string())?;
+        Ok(Box::new(encoder))
+    }
+}
+
+impl OpusDecode for Decoder {
+    fn decode(&mut self, packet: &[u8], output: &mut [i16]) -> Result<usize, String> {
+        Decoder::decode(self, packet, output, false).map_err(|e| e.to_string())
+    }
+}
+
+impl OpusEncode for Encoder {
+    fn encode(&mut self, pcm: &[i16], output: &mut [u8]) -> Result<usize, String> {
+        Encoder::encode(self, pcm, output).map_err(|e| e.to_string())
+    }
+
+    fn set_bitrate(&mut self, bits_per_second: i32) -> Result<(), String> {
+        Encoder::set_bitrate(self, Bitrate::Bits(bits_per_second)).map_err(|e| e.to_string())
+    }
+}
+
+/// Test codecs carrying raw 16-bit little-endian PCM, so a frame comes out exactly as it went in.
+/// A packet with an odd byte count fails to decode, standing in for a corrupt one, and a silent
+/// frame encodes to nothing, as it would under DTX.
+#[cfg(test)]
+pub struct PcmPassthrough;
+
+#[cfg(test)]
+struct PassthroughDecoder;
+
+#[cfg(test)]
+struct PassthroughEncoder;
+
+#[cfg(test)]
+impl CodecFactory for PcmPassthrough {
+    fn decoder(&self, _sample_rate: u32) -> Result<Box<dyn OpusDecode>, String> {
+        Ok(Box::new(PassthroughDecoder))
+    }
+
+    fn encoder(&self, _sample_rate: u32, _stereo: bool) -> Result<Box<dyn OpusEncode>, String> {
+        Ok(Box::new(PassthroughEncoder))
+    }
+}
+
+#[cfg(test)]
+impl OpusDecode for PassthroughDecoder {
+    fn decode(&mut self, packet: &[u8], output: &mut [i16]) -> Result<usize, String> {
+        if packet.is_empty() {
+            output.fill(0);
+            return Ok(output.len());
+        }
+        let chunks = packet.chunks_exact(2);
+        if !chunks.remainder().is_empty() || chunks.len() > output.len() {
+            return Err("corrupted stream".to_string());
+        }
+        for (sample, bytes) in output.iter_mut().zip(chunks) {
+            *sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(packet.len() / 2)
+    }
+}
+
+#[cfg(test)]
+impl OpusEncode for PassthroughEncoder {
+    fn encode(&mut self, pcm: &[i16], output: &mut [u8]) -> Result<usize, String> {
+        if pcm.iter().all(|&sample| sample == 0) {
+            return Ok(0);
+        }
+        if pcm.len() * 2 > output.len() {
+            return Err("buffer too small".to_string());
+        }
+        for (bytes, sample) in output.chunks_exact_mut(2).zip(pcm) {
+            bytes.copy_from_slice(&sample.to_le_bytes());
+        }
+        Ok(pcm.len() * 2)
+    }
+
+    fn set_bitrate(&mut self, _bits_per_second: i32) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -1,24 +1,119 @@
 use hyperprocess_macro::hyperprocess;
 use hyperware_process_lib::http::server::{send_ws_push, WsMessageType};
-use hyperware_process_lib::{println, LazyLoadBlob, our};
+use hyperware_process_lib::{println, Address, LazyLoadBlob, Request, our};
 use hyperware_app_common::source;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use rand::seq::SliceRandom;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use base64::{Engine as _, engine::general_purpose};
 
 mod audio;
-use audio::AudioProcessor;
+mod codec;
+mod ogg;
+mod transcription;
+use transcription::SegmentSink;
+use audio::{AudioCapabilities, AudioCodec, AudioConfig, AudioProcessor, AudioStats, OutputProfile, DEFAULT_NODE_DECODER_LIMIT, DEFAULT_STREAM, FRAME_SIZE, SAMPLE_RATE};
 
 const ICON: &str = include_str!("./icon");
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+const DEFAULT_RECONNECT_WINDOW_SECS: u64 = 20;
+const DEFAULT_MAX_CHAT_HISTORY: usize = 500;
+const WS_CLOSE_NORMAL: u16 = 1000; // RFC 6455 normal closure
+const WS_CLOSE_GOING_AWAY: u16 = 1001;
+// Clients heartbeat every 30s, so a channel silent for three of those is presumed dead. Pushes
+// report nothing back, so this is the only way to notice one whose Close frame never came.
+const SILENT_CHANNEL_TIMEOUT_MS: u64 = 90 * 1000;
+const DEFAULT_PING_INTERVAL_MS: u64 = 20 * 1000;
+const DEFAULT_PING_MISS_THRESHOLD: u32 = 3; // Unanswered pings in a row before the channel is dropped
+const HOUSEKEEPING_INTERVAL_MS: u64 = 1000;
+const CALL_TOMBSTONE_TTL_MS: u64 = 60 * 60 * 1000; // Remember ended calls for an hour
+const UNJOINED_CALL_TTL_SECS: u64 = 15 * 60; // Calls nobody has joined by then are cleaned up
+const CREATE_CALL_WINDOW_MS: u64 = 60 * 1000;
+const MAX_CALLS_CREATED_PER_WINDOW: usize = 10; // Per source node, within CREATE_CALL_WINDOW_MS
+const JOIN_WINDOW_MS: u64 = 10 * 1000;
+const MAX_JOINS_PER_WINDOW: usize = 5; // Per joiner, within JOIN_WINDOW_MS, before backing off
+const JOIN_BACKOFF_BASE_MS: u64 = 2 * 1000; // Doubles with each consecutive violation...
+const JOIN_BACKOFF_MAX_MS: u64 = 2 * 60 * 1000; // ...up to this
+const SLOT_RESERVATION_TTL_MS: u64 = 60 * 1000; // A handshaked node that hasn't joined by then loses its slot
+const MAX_ACTIVE_CALLS: usize = 200;
+const MAX_AUDIO_BATCH_FRAMES: u32 = 5;
+const MAX_REPACKETIZED_FRAMES: usize = 3; // Longest combined packet, in 20ms frames
+const AUDIO_BATCH_MAX_DELAY_MS: u64 = 60; // Flush a partial batch once its oldest frame is this old
+const FRAME_DURATION_MS: u64 = 20;
+const MIN_PLAYOUT_REPORT_INTERVAL_MS: u64 = 1000; // Per participant; faster PlayoutStats are dropped
+const MAX_METADATA_KEYS: usize = 16; // Per participant
+const MAX_METADATA_KEY_CHARS: usize = 64;
+const MAX_METADATA_VALUE_CHARS: usize = 1024;
+const MAX_PINNED_MESSAGE_CHARS: usize = 2000;
+const MAX_LOGO_URL_CHARS: usize = 2048;
+const MAX_CALL_ALIAS_CHARS: usize = 64;
+const CHAT_CATCH_UP_FALLBACK: usize = 50; // Recent messages sent when a client's last seen one is unknown
+const CALL_ENDING_SOON_WARNINGS_SECS: [u64; 2] = [5 * 60, 60]; // Before a call hits its max duration
+const SILENCE_ADVISORY_AFTER_SECS: u64 = 30; // How long every speaker must have been quiet
+const SILENCE_ADVISORY_COOLDOWN_SECS: u64 = 5 * 60; // At most one advisory per call this often
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Role {
     Listener,
     Chatter,
     Speaker,
     Admin,
+    Custom(String), // Defined per call; see `Call::custom_roles`
+}
+
+impl Role {
+    /// Permissions for the preset roles. Custom roles are looked up on their call.
+    fn preset_permissions(&self) -> Option<Permissions> {
+        match self {
+            Role::Listener => Some(Permissions::REACT),
+            Role::Chatter => Some(Permissions::CHAT.union(Permissions::REACT)),
+            Role::Speaker => Some(Permissions::SPEAK.union(Permissions::CHAT).union(Permissions::REACT).union(Permissions::SHARE)),
+            Role::Admin => Some(Permissions::ALL),
+            Role::Custom(_) => None,
+        }
+    }
+}
+
+/// What a role may do, as a bitset so custom roles can combine abilities freely.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const SPEAK: Permissions = Permissions(1 << 0);
+    pub const CHAT: Permissions = Permissions(1 << 1);
+    pub const REACT: Permissions = Permissions(1 << 2);
+    pub const MODERATE: Permissions = Permissions(1 << 3);
+    pub const SHARE: Permissions = Permissions(1 << 4);
+    pub const MUTE_OTHERS: Permissions = Permissions(1 << 5); // The one slice of moderation co-hosts get
+    pub const ALL: Permissions = Permissions(0b11_1111);
+
+    pub const fn union(self, other: Permissions) -> Permissions {
+        Permissions(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn can_speak(self) -> bool {
+        self.contains(Permissions::SPEAK)
+    }
+
+    pub fn can_chat(self) -> bool {
+        self.contains(Permissions::CHAT)
+    }
+
+    pub fn can_moderate(self) -> bool {
+        self.contains(Permissions::MODERATE)
+    }
+
+    pub fn can_share(self) -> bool {
+        self.contains(Permissions::SHARE)
+    }
+
+    pub fn can_mute_others(self) -> bool {
+        self.contains(Permissions::MUTE_OTHERS) || self.can_moderate()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +126,38 @@ pub enum ConnectionType {
 #[serde(rename_all = "camelCase")]
 pub struct CreateCallReq {
     pub default_role: Role,
+    pub default_role_node: Option<Role>, // For node-authenticated joiners; defaults to default_role
+    pub default_role_browser: Option<Role>, // For anonymous browser joiners; defaults to default_role
+    pub reconnect_window_secs: Option<u64>, // How long a dropped participant may resume; 0 disables
+    pub push_to_talk: Option<bool>, // Only one speaker holds the floor at a time
+    pub speakers_can_mute: Option<bool>, // Let Speakers mute and unmute others, as co-hosts
+    pub host_controls_mute: Option<bool>, // Participants may only unmute once a moderator has unmuted them
+    pub persistent: Option<bool>, // Keep the room open when the host leaves or it empties
+    pub max_participants: Option<usize>, // Unset means no cap
+    pub hide_node_ids: Option<bool>, // Show node joiners to others by a friendly name only
+    pub audio_config: Option<AudioConfig>,
+    pub max_chat_history: Option<usize>, // Oldest messages are evicted beyond this
+    pub custom_roles: Option<HashMap<String, Permissions>>,
+    pub idle_speaker_timeout_secs: Option<u64>, // Demote Speakers silent this long to Listener; unset disables
+    pub max_screen_shares: Option<usize>, // Simultaneous screen sharers; defaults to 1
+    pub max_duration_secs: Option<u64>, // Counted from creation; the call is ended once it's reached
+    pub branding: Option<CallBranding>,
+}
+
+/// How a room presents itself, so organizations can run branded rooms on a shared node.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallBranding {
+    pub logo_url: Option<String>, // http(s) only
+    pub accent_color: Option<String>, // "#rgb" or "#rrggbb", stored lowercase
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinLink {
+    pub call_id: String,
+    pub host_node: String,
+    pub path: String, // e.g. "/voice:voice:ware.hypr/call/<call-id>"; prefix with the node's origin
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +167,9 @@ pub struct CallInfo {
     pub created_at: u64,
     pub participant_count: u32,
     pub default_role: Role,
+    pub push_to_talk: bool,
+    pub persistent: bool,
+    pub host_controls_mute: bool, // Hide the unmute button unless you can moderate or hold an unmute grant
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -64,17 +194,154 @@ pub struct CallState {
     pub call_info: CallInfo,
     pub participants: Vec<ParticipantInfo>,
     pub chat_history: Vec<ChatMessage>,
+    pub pinned_message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParticipantCount {
+    pub participant_count: usize,
+    pub created_at: u64,
+}
+
+/// What a lobby needs to know before opening a WebSocket to join a call.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinCheck {
+    pub exists: bool,
+    pub is_full: bool,
+    pub is_locked: bool,
+    pub requires_password: bool,
+    pub requires_admit: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParticipantLocation {
+    pub call_id: String,
+    pub role: Role,
+}
+
+/// Snapshot of the WebSocket bookkeeping, for debugging mapping bugs without the log spam.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionMap {
+    pub connections: HashMap<u32, String>,
+    pub participant_channels: HashMap<String, u32>,
+    pub call_channels: HashMap<String, Vec<u32>>,
+    pub participant_output_sequences: HashMap<String, u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecoderUsage {
+    pub live: usize,
+    pub limit: usize,
+}
+
+/// How often the server pings joined WebSocket clients and how many unanswered pings it tolerates.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeepaliveConfig {
+    pub interval_ms: u64, // No finer than HOUSEKEEPING_INTERVAL_MS, which drives the pings
+    pub miss_threshold: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval_ms: DEFAULT_PING_INTERVAL_MS,
+            miss_threshold: DEFAULT_PING_MISS_THRESHOLD,
+        }
+    }
+}
+
+/// What `repair_connection_state` found wrong and fixed. All empty means the maps agreed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyReport {
+    pub orphaned_channels: Vec<u32>, // Closed: no participant, or superseded by a newer channel
+    pub stale_mappings: usize, // participant_channels/call_channels entries dropped or restored
+    pub channelless_participants: Vec<String>, // Treated as disconnected
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallStats {
+    pub audio: AudioStats,
+    pub bytes_sent: HashMap<String, u64>, // Audio payload bytes pushed to each participant
+    pub playout: HashMap<String, PlayoutSummary>, // Client-reported jitter buffer health per participant
+}
+
+/// A participant's PlayoutStats reports, aggregated since the call began or its stats were reset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayoutSummary {
+    pub reports: u64,
+    pub last_buffer_ms: u32,
+    pub mean_buffer_ms: f64,
+    pub max_buffer_ms: u32,
+    pub underruns: u64,
+    pub late_frames: u64,
+    pub last_report_at: u64, // ms
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ParticipantInfo {
-    pub id: String,
+    pub id: String, // Stable for the life of the call; for display use display_name
+    pub seat: u32, // Stable join order for layout; see `Call::next_seat`
     pub display_name: String,
+    pub node_id: Option<String>, // The node behind a node-authenticated joiner, unless the call hides it
     pub role: Role,
     pub is_muted: bool,
+    pub is_deafened: bool,
+    pub is_speaking: bool,
+    pub hand_raised: bool,
+    pub unmute_granted: bool, // Under host_controls_mute, may unmute themselves
+    pub reconnecting: bool, // Dropped but still within the reconnect window
     pub settings: UserSettings,
     pub avatar_url: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+/// A moderation action, kept per call for admins. Separate from chat and never broadcast.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub actor_id: String,
+    pub target_id: Option<String>,
+    pub action: AuditAction,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AuditAction {
+    RoleChanged { from: Role, to: Role },
+    RoleDefined { name: String, permissions: Permissions },
+    SpeakerPinned { pinned: bool },
+    FloorRevoked,
+}
+
+/// One line of the analytics feed sent to `subscribe_analytics` subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyticsEvent {
+    pub call_id: String,
+    pub timestamp: u64, // ms
+    pub event: AnalyticsEventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnalyticsEventKind {
+    CallCreated,
+    #[serde(rename_all = "camelCase")]
+    ParticipantJoined { participant_id: String, role: Role }, // Resuming within the reconnect window isn't a join
+    #[serde(rename_all = "camelCase")]
+    ParticipantLeft { participant_id: String },
+    #[serde(rename_all = "camelCase")]
+    RoleChanged { participant_id: String, from: Role, to: Role },
+    CallEnded { reason: CallEndReason }, // Anyone still in the call leaves with it
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,13 +352,48 @@ pub struct ChatMessage {
     pub sender_name: String,
     pub content: String,
     pub timestamp: u64,
+    #[serde(default)] // Clients and older peers send ChatMessages without a kind; those were typed by people
+    pub kind: ChatMessageKind,
+}
+
+/// What produced a chat message, so clients can style or filter history.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatMessageKind {
+    #[default]
+    User,
+    System,
+    Announcement,
+    Join,
+    Leave,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeCallsReq {
+    pub source_call_id: String, // Ended once everyone has moved
+    pub dest_call_id: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct LeaveCallReq {
+pub struct GetAuditLogReq {
     pub call_id: String,
-    pub participant_id: String,
+    pub auth_token: String, // The requester's token from JoinSuccess
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetParticipantNodesReq {
+    pub call_id: String,
+    pub auth_token: String, // The requester's token from JoinSuccess
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetCallAliasReq {
+    pub call_id: String,
+    pub alias: String, // Empty to clear the call's alias
+    pub auth_token: String, // The requester's token from JoinSuccess
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +409,7 @@ pub struct UpdateRoleReq {
 #[serde(rename_all = "camelCase")]
 pub struct NodeHandshakeReq {
     pub call_id: String,
+    pub reserve_slot: Option<bool>, // Hold a seat in a capped call until this node joins
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -115,23 +418,101 @@ pub struct NodeHandshakeResp {
     pub auth_token: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BotJoinReq {
+    pub call_id: String,
+    pub auth_token: String, // From `node_handshake`
+    pub display_name: Option<String>,
+    pub capabilities: Option<DecodeCapabilities>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BotMessageReq {
+    pub channel_id: u32,
+    pub message: WsClientMessage,
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WsClientMessage {
     #[serde(rename_all = "camelCase")]
-    JoinCall { call_id: String, auth_token: Option<String>, display_name: Option<String>, settings: Option<UserSettings>, avatar_url: Option<String> },
+    JoinCall { call_id: String, auth_token: Option<String>, display_name: Option<String>, settings: Option<UserSettings>, avatar_url: Option<String>, resume_token: Option<String>, capabilities: Option<DecodeCapabilities>, batch_frames: Option<u32>, repacketize: Option<bool>, last_seen_message_id: Option<String> },
     Chat(String),
     Mute(bool),
     #[serde(rename_all = "camelCase")]
-    AudioData { data: String, sample_rate: u32, channels: u32, sequence: Option<u32>, timestamp: Option<u64> },
+    AudioData { data: String, sample_rate: u32, channels: u32, sequence: Option<u32>, timestamp: Option<u64>, stream: Option<String> }, // `stream` tags e.g. "mic" vs "system"; defaults to "mic"
     #[serde(rename_all = "camelCase")]
     UpdateRole { target_id: String, new_role: Role },
+    #[serde(rename_all = "camelCase")]
+    UpdateRolesBulk { from_role: Role, to_role: Role }, // Everyone currently holding from_role gets to_role
+    DefineRole { name: String, permissions: Permissions },
+    #[serde(rename_all = "camelCase")]
+    PinSpeaker { target_id: String, pinned: bool },
+    PlayChime { kind: ChimeKind },
+    SetScreenShare { active: bool },
+    SetPinnedMessage { content: String }, // Empty clears the pin
     UpdateSettings(UserSettings),
     #[serde(rename_all = "camelCase")]
     UpdateSpeakingState { is_speaking: bool },
     #[serde(rename_all = "camelCase")]
-    UpdateAvatar { avatar_url: Option<String> },
+    UpdateAvatar { avatar_url: Option<String> }, // None or a blank URL removes the avatar
+    SetMetadata { key: String, value: String }, // Free-form data for integrations; an empty value removes the key
+    RequestFloor,
+    ReleaseFloor,
+    RaiseHand(bool), // false lowers it again
+    #[serde(rename_all = "camelCase")]
+    SetParticipantMuted { target_id: String, muted: bool }, // Needs MUTE_OTHERS; see `Permissions`. Unmuting only lets the target unmute themselves
+    SetDeafened { deafened: bool }, // Deafening also mutes
+    #[serde(rename_all = "camelCase")]
+    SetMutePolicy { host_controls_mute: bool }, // Moderators only
+    LowerAllHands, // Moderators only
+    Resync(Option<String>), // Answered with FullState; given the last chat message id seen, only newer chat is sent
+    #[serde(rename_all = "camelCase")]
+    PlayoutStats { buffer_ms: u32, underruns: u32, late_frames: u32 }, // Counts since the previous report; at most one per MIN_PLAYOUT_REPORT_INTERVAL_MS
     Heartbeat,
+    Pong, // Answers the server's Ping
+    Leave, // Leave for good, skipping the reconnect window; the server then closes the channel
+}
+
+/// Sounds a host can ring for the whole room. Clients map each to a local sound file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ChimeKind {
+    Attention,
+    Start,
+    End,
+}
+
+/// What a client can decode, advertised on join so its mix can be encoded to match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodeCapabilities {
+    pub max_bitrate: Option<i32>,
+    #[serde(default)]
+    pub stereo: bool,
+    pub max_bandwidth: Option<AudioBandwidth>,
+    pub sample_rate: Option<u32>, // Rate to encode this client's mix at; defaults to the mixer rate
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBandwidth {
+    Narrowband,
+    Wideband,
+    Fullband,
+}
+
+impl DecodeCapabilities {
+    fn output_profile(&self) -> OutputProfile {
+        // Opus picks its audio bandwidth from the bitrate, so a bandwidth limit is a bitrate ceiling
+        let bandwidth_ceiling = match self.max_bandwidth {
+            Some(AudioBandwidth::Narrowband) => Some(12_000),
+            Some(AudioBandwidth::Wideband) => Some(24_000),
+            Some(AudioBandwidth::Fullband) | None => None,
+        };
+        OutputProfile::new(self.max_bitrate, bandwidth_ceiling, self.stereo, self.sample_rate)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -166,7 +547,9 @@ pub struct WsAudioData {
     pub participant_id: String,
     pub data: String,
     pub sequence: Option<u32>,
-    pub timestamp: Option<u64>,
+    pub timestamp: Option<u64>, // Call clock: ms since the call was created, strictly increasing
+    pub source_timestamp: Option<u64>, // The lone speaker's own input timestamp, when there is one
+    pub stream_epoch: Option<u32>, // Bumped whenever `sequence` restarts from 0; flush on a change
     pub sample_rate: Option<u32>,
     pub channels: Option<u32>,
 }
@@ -174,22 +557,64 @@ pub struct WsAudioData {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WsServerMessage {
     #[serde(rename_all = "camelCase")]
-    JoinSuccess { participant_id: String, role: Role, participants: Vec<ParticipantInfo>, chat_history: Vec<ChatMessage>, auth_token: String, host_id: Option<String> },
+    JoinSuccess { participant_id: String, role: Role, participants: Vec<ParticipantInfo>, chat_history: Vec<ChatMessage>, auth_token: String, host_id: Option<String>, push_to_talk: bool, host_controls_mute: bool, floor_holder: Option<String>, custom_roles: HashMap<String, Permissions>, pinned_speakers: Vec<String>, screen_sharers: Vec<String>, pinned_message: Option<String>, codec: AudioCodec, branding: CallBranding },
+    #[serde(rename_all = "camelCase")]
+    FullState { participants: Vec<ParticipantInfo>, chat_history: Vec<ChatMessage>, host_id: Option<String>, push_to_talk: bool, host_controls_mute: bool, floor_holder: Option<String>, custom_roles: HashMap<String, Permissions>, pinned_speakers: Vec<String>, screen_sharers: Vec<String>, pinned_message: Option<String> },
     Chat(WsChatMessage),
     ParticipantJoined(WsParticipantJoined),
     #[serde(rename_all = "camelCase")]
     ParticipantLeft { participant_id: String },
     RoleUpdated(WsRoleUpdate),
+    #[serde(rename_all = "camelCase")]
+    RolesUpdated { participant_ids: Vec<String>, new_role: Role },
+    SpeakingEnabled { enabled: bool }, // Only to you, when a role change grants or takes away speaking
+    UnmuteAllowed, // Only to you, when a moderator lifts your mute; you stay muted until you unmute
+    AudioPermissionDenied, // Only to you, once, when you send audio without speaking rights; re-armed by a role change
+    Ping, // Answer with Pong; see `KeepaliveConfig`
+    RoleDefined { name: String, permissions: Permissions },
+    #[serde(rename_all = "camelCase")]
+    SpeakerPinned { participant_id: String, pinned: bool },
+    Chime { kind: ChimeKind },
+    #[serde(rename_all = "camelCase")]
+    ScreenShareUpdated { participant_id: String, active: bool },
+    PinnedMessageUpdated { content: Option<String> },
+    EchoDetected, // Your mic is picking up the call, so you've been muted until you unmute
+    #[serde(rename_all = "camelCase")]
+    Caption { participant_id: String, text: String, is_final: bool },
+    #[serde(rename_all = "camelCase")]
+    CallMerged { call_id: String }, // You've been moved into this call; a JoinSuccess for it follows
     ParticipantMuted(WsParticipantMuted),
     AudioData(WsAudioData),
+    AudioDataBatch { frames: Vec<WsAudioData> },
     #[serde(rename_all = "camelCase")]
     SettingsUpdated { participant_id: String, settings: UserSettings },
     #[serde(rename_all = "camelCase")]
     SpeakingStateUpdated { participant_id: String, is_speaking: bool },
     #[serde(rename_all = "camelCase")]
     AvatarUpdated { participant_id: String, avatar_url: Option<String> },
+    #[serde(rename_all = "camelCase")]
+    MetadataUpdated { participant_id: String, key: String, value: Option<String> },
+    #[serde(rename_all = "camelCase")]
+    HandRaised { participant_id: String, raised: bool },
+    #[serde(rename_all = "camelCase")]
+    HandsLowered { participant_ids: Vec<String> }, // Several hands at once, e.g. LowerAllHands
+    #[serde(rename_all = "camelCase")]
+    DeafenedUpdated { participant_id: String, deafened: bool },
+    #[serde(rename_all = "camelCase")]
+    MutePolicyUpdated { host_controls_mute: bool },
+    #[serde(rename_all = "camelCase")]
+    ParticipantReconnecting { participant_id: String, reconnecting: bool }, // Entering/leaving the reconnect window
+
+    #[serde(rename_all = "camelCase")]
+    FloorGranted { participant_id: String },
+    FloorReleased,
+    #[serde(rename_all = "camelCase")]
+    CallEndingSoon { seconds_remaining: u64 },
+    // Nobody who may speak has been heard for a while; clients can ask "Is your mic muted?"
+    #[serde(rename_all = "camelCase")]
+    SilenceAdvisory { all_muted: bool }, // false: some are unmuted but no audio is arriving
     Error(String),
-    CallEnded,
+    CallEnded { reason: CallEndReason },
     CloseConnection, // New message to tell frontend to close its WebSocket
 }
 
@@ -197,17 +622,96 @@ pub enum WsServerMessage {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 struct VoiceState {
     calls: HashMap<String, Call>,
+    call_aliases: HashMap<String, String>, // Vanity alias -> call_id; at most one alias per call
     connections: HashMap<u32, String>, // channel_id -> participant_id
     participant_channels: HashMap<String, u32>, // participant_id -> channel_id
     call_channels: HashMap<String, HashSet<u32>>, // call_id -> set of channel_ids
     word_dictionary: Vec<String>,
     used_pleb_names: HashMap<String, Vec<String>>,
-    node_auth_tokens: HashMap<String, String>, // auth_token -> node_id
+    node_auth_tokens: HashMap<String, NodeAuthToken>, // auth_token -> node/call it was issued for
     host_settings: UserSettings, // Host's default settings
+    last_housekeeping: u64, // Timestamp (ms) of the last periodic cleanup pass
+    ended_calls: HashMap<String, CallTombstone>, // Recently ended calls, so stale links get a clear answer
+    decoder_limit: Option<usize>, // Node-wide speaker decoder budget; DEFAULT_NODE_DECODER_LIMIT when unset
+    keepalive: Option<KeepaliveConfig>, // Server-to-client pings; the defaults when unset
     #[serde(skip)]
     audio_processors: HashMap<String, Arc<Mutex<AudioProcessor>>>, // Per call audio processor
     #[serde(skip)]
     participant_output_sequences: HashMap<String, u32>, // Track output sequence numbers per participant
+    #[serde(skip)]
+    audio_violations: HashMap<String, u32>, // Audio frames dropped per participant for lacking permission or a decoder
+    #[serde(skip)]
+    audio_batches: HashMap<String, AudioBatch>, // Outgoing frames waiting to be sent together, per participant
+    #[serde(skip)]
+    recent_call_creations: HashMap<String, Vec<u64>>, // source node -> creation timestamps within the window
+    #[serde(skip)]
+    join_attempts: HashMap<String, JoinAttempts>, // Joiner identity -> recent (re)join attempts
+    #[serde(skip)]
+    channel_last_seen: HashMap<u32, u64>, // WebSocket channel -> when it last sent us anything
+    #[serde(skip)]
+    channel_pings: HashMap<u32, ChannelPings>, // WebSocket channel -> our pings it hasn't answered
+    #[serde(skip)]
+    analytics_subscribers: Vec<Address>, // Local processes receiving the analytics feed
+    #[serde(skip)]
+    bot_channels: HashMap<u32, Address>, // Virtual channel -> the local process it stands in for
+}
+
+/// A joiner's recent (re)join attempts, for throttling clients stuck in a reconnect loop.
+#[derive(Debug, Clone, Default)]
+struct JoinAttempts {
+    recent: Vec<u64>, // Timestamps within JOIN_WINDOW_MS
+    strikes: u32, // Consecutive windows in which the limit was hit
+    blocked_until: u64,
+}
+
+/// Server pings sent to one channel since it last answered with Pong.
+#[derive(Debug, Clone, Default)]
+struct ChannelPings {
+    last_sent: u64,
+    unanswered: u32,
+}
+
+#[derive(Debug, Clone)]
+struct AudioBatch {
+    frames: Vec<WsAudioData>,
+    started_at: u64, // When the oldest frame was queued (ms)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CallEndReason {
+    HostLeft,
+    Empty,
+    NeverJoined,
+    MergedInto(String),
+    MaxDurationReached,
+    ForceEnded,
+    Shutdown,
+}
+
+impl CallEndReason {
+    fn describe(&self) -> String {
+        match self {
+            CallEndReason::HostLeft => "the host left".to_string(),
+            CallEndReason::Empty => "everyone left".to_string(),
+            CallEndReason::NeverJoined => "nobody joined in time".to_string(),
+            CallEndReason::MergedInto(call_id) => format!("it was merged into {}", call_id),
+            CallEndReason::MaxDurationReached => "it reached its maximum duration".to_string(),
+            CallEndReason::ForceEnded => "it was ended by the node operator".to_string(),
+            CallEndReason::Shutdown => "the server shut down".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CallTombstone {
+    ended_at: u64,
+    reason: CallEndReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NodeAuthToken {
+    node_id: String,
+    call_id: String, // Tokens are only valid for, and pruned with, the call they were minted for
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -217,8 +721,207 @@ struct Call {
     chat_history: Vec<ChatMessage>,
     created_at: u64,
     default_role: Role,
+    default_role_node: Option<Role>, // Overrides default_role for node-authenticated joiners
+    default_role_browser: Option<Role>, // Overrides default_role for browser joiners
     creator_id: Option<String>,
     host_id: Option<String>, // The participant who mixes audio
+    reconnect_window_secs: u64, // Grace period before a dropped participant is removed
+    // Seats are handed out in join order and never reused, so they stay sparse after leaves.
+    // A resumed participant keeps their seat.
+    next_seat: u32,
+    push_to_talk: bool,
+    speakers_can_mute: bool, // Grants Speakers MUTE_OTHERS
+    // Self-unmute needs a grant, given by a moderator unmuting you; self-mute is always allowed.
+    // Moderators are exempt. Turning it on mutes everyone else who holds no grant.
+    host_controls_mute: bool,
+    // Survives the host leaving and running empty; only an explicit end or max duration closes it.
+    // While empty it has no host, and the next joiner takes over mixing.
+    persistent: bool,
+    max_participants: Option<usize>,
+    // Node joiners get opaque participant ids and pleb names by default, and ParticipantInfo leaves
+    // out their node; moderators can still look nodes up with get_participant_nodes
+    hide_node_ids: bool,
+    // Node -> when its handshake reservation lapses, in ms. Reservations count towards
+    // max_participants, so browsers can't fill the seats handshaked nodes are about to take.
+    reserved_slots: HashMap<String, u64>,
+    floor_holder: Option<String>, // Push-to-talk: the only participant whose audio is mixed
+    audio_config: AudioConfig,
+    max_chat_history: usize,
+    custom_roles: HashMap<String, Permissions>, // Host-defined roles by name
+    pinned_speakers: HashSet<String>, // Always mixed at full gain; see `AudioProcessor::set_pinned`
+    audit_log: Vec<AuditEntry>, // Append-only
+    idle_speaker_timeout_secs: Option<u64>,
+    screen_sharers: Vec<String>, // In the order they started sharing
+    max_screen_shares: usize,
+    max_duration_secs: Option<u64>,
+    pinned_message: Option<String>, // One persistent message shown to everyone, e.g. an agenda
+    clock_origin_ms: u64,
+    mix_clock_samples: Option<u64>, // Position of the last mix on the call clock, in mixer samples
+    ending_soon_warnings_sent: usize, // How many of CALL_ENDING_SOON_WARNINGS_SECS have gone out
+    last_silence_advisory: Option<u64>, // Seconds since epoch; see SILENCE_ADVISORY_COOLDOWN_SECS
+    bytes_sent: HashMap<String, u64>, // Outbound audio per participant since the call began or was reset
+    playout_stats: HashMap<String, PlayoutSummary>, // Reset along with bytes_sent
+    branding: CallBranding,
+}
+
+impl Call {
+    fn permissions(&self, role: &Role) -> Permissions {
+        match role {
+            Role::Custom(name) => self.custom_roles.get(name).copied().unwrap_or_default(),
+            Role::Speaker if self.speakers_can_mute => {
+                Role::Speaker.preset_permissions().unwrap_or_default().union(Permissions::MUTE_OTHERS)
+            }
+            preset => preset.preset_permissions().unwrap_or_default(),
+        }
+    }
+
+    /// The JoinSuccess for a participant already in this call, carrying their resume token.
+    fn join_success(&self, participant_id: &str) -> Option<WsServerMessage> {
+        self.join_success_since(participant_id, None)
+    }
+
+    /// JoinSuccess carrying only the chat after `last_seen_message_id`; see `chat_since`.
+    fn join_success_since(&self, participant_id: &str, last_seen_message_id: Option<&str>) -> Option<WsServerMessage> {
+        let participant = self.participants.get(participant_id)?;
+        Some(WsServerMessage::JoinSuccess {
+            participant_id: participant.id.clone(),
+            role: participant.role.clone(),
+            participants: self.participant_infos(),
+            chat_history: self.chat_since(last_seen_message_id),
+            auth_token: participant.resume_token.clone(),
+            host_id: self.host_id.clone(),
+            push_to_talk: self.push_to_talk,
+            host_controls_mute: self.host_controls_mute,
+            floor_holder: self.floor_holder.clone(),
+            custom_roles: self.custom_roles.clone(),
+            pinned_speakers: self.pinned_speakers.iter().cloned().collect(),
+            screen_sharers: self.screen_sharers.clone(),
+            pinned_message: self.pinned_message.clone(),
+            codec: self.audio_config.codec,
+            branding: self.branding.clone(),
+        })
+    }
+
+    /// Everything a client shows about the call, for one that may have missed broadcasts.
+    fn full_state(&self) -> WsServerMessage {
+        self.full_state_since(None)
+    }
+
+    /// FullState carrying only the chat after `last_seen_message_id`; see `chat_since`.
+    fn full_state_since(&self, last_seen_message_id: Option<&str>) -> WsServerMessage {
+        WsServerMessage::FullState {
+            participants: self.participant_infos(),
+            chat_history: self.chat_since(last_seen_message_id),
+            host_id: self.host_id.clone(),
+            push_to_talk: self.push_to_talk,
+            host_controls_mute: self.host_controls_mute,
+            floor_holder: self.floor_holder.clone(),
+            custom_roles: self.custom_roles.clone(),
+            pinned_speakers: self.pinned_speakers.iter().cloned().collect(),
+            screen_sharers: self.screen_sharers.clone(),
+            pinned_message: self.pinned_message.clone(),
+        }
+    }
+
+    /// Chat a client hasn't seen yet: everything after `last_seen_message_id`, the whole history when
+    /// they've seen nothing, or the last CHAT_CATCH_UP_FALLBACK messages when their last seen one has
+    /// been evicted (or never existed) and there's no telling what they missed.
+    fn chat_since(&self, last_seen_message_id: Option<&str>) -> Vec<ChatMessage> {
+        let Some(last_seen) = last_seen_message_id else {
+            return self.chat_history.clone();
+        };
+        match self.chat_history.iter().position(|message| message.id == last_seen) {
+            Some(index) => self.chat_history[index + 1..].to_vec(),
+            None => {
+                let start = self.chat_history.len().saturating_sub(CHAT_CATCH_UP_FALLBACK);
+                self.chat_history[start..].to_vec()
+            }
+        }
+    }
+
+    /// Timestamp for a mix on the call clock, in milliseconds since creation. The clock counts the
+    /// samples actually mixed, advancing one frame per mix however unevenly mixes arrive. When no
+    /// mix was made for a while (DTX, silence) it skips ahead by whole frames to catch up with the
+    /// wall clock, so the gap shows up in the timestamps too.
+    fn next_mix_timestamp(&mut self, now_ms: u64) -> u64 {
+        let frame_samples = FRAME_SIZE as u64;
+        let wall_samples = now_ms.saturating_sub(self.clock_origin_ms) * SAMPLE_RATE as u64 / 1000;
+        let samples = match self.mix_clock_samples {
+            Some(previous) => {
+                let skipped_frames = wall_samples.saturating_sub(previous) / frame_samples;
+                previous + frame_samples * skipped_frames.max(1)
+            }
+            None => wall_samples,
+        };
+        self.mix_clock_samples = Some(samples);
+        samples * 1000 / SAMPLE_RATE as u64
+    }
+
+    /// Evict the oldest chat messages beyond `max_chat_history`.
+    fn trim_chat_history(&mut self) {
+        if self.chat_history.len() > self.max_chat_history {
+            let excess = self.chat_history.len() - self.max_chat_history;
+            self.chat_history.drain(..excess);
+        }
+    }
+
+    fn audit(&mut self, actor_id: &str, target_id: Option<&str>, action: AuditAction) {
+        self.audit_log.push(AuditEntry {
+            actor_id: actor_id.to_string(),
+            target_id: target_id.map(str::to_string),
+            action,
+            timestamp: current_timestamp().unwrap_or(0),
+        });
+    }
+
+    /// Whether a call with a cap has no seat left for this joiner. Unexpired reservations count, other
+    /// than the joiner's own.
+    fn is_full_for(&self, node_id: Option<&str>, now_ms: u64) -> bool {
+        let Some(max_participants) = self.max_participants else {
+            return false;
+        };
+        let reserved = self.reserved_slots.iter()
+            .filter(|(reserved_for, &expires_at)| Some(reserved_for.as_str()) != node_id && expires_at > now_ms)
+            .count();
+        self.participants.len() + reserved >= max_participants
+    }
+
+    /// The role a joiner gets unless they're the first in.
+    fn default_role_for(&self, connection_type: &ConnectionType) -> Role {
+        let role = match connection_type {
+            ConnectionType::Node(_) => self.default_role_node.as_ref(),
+            ConnectionType::Browser => self.default_role_browser.as_ref(),
+        };
+        role.unwrap_or(&self.default_role).clone()
+    }
+
+    fn has_role(&self, role: &Role) -> bool {
+        match role {
+            Role::Custom(name) => self.custom_roles.contains_key(name),
+            _ => true,
+        }
+    }
+
+    fn info(&self) -> CallInfo {
+        CallInfo {
+            id: self.id.clone(),
+            created_at: self.created_at,
+            participant_count: self.participants.len() as u32,
+            default_role: self.default_role.clone(),
+            push_to_talk: self.push_to_talk,
+            persistent: self.persistent,
+            host_controls_mute: self.host_controls_mute,
+        }
+    }
+
+    /// Participant infos ordered by seat, so clients get a stable layout.
+    fn participant_infos(&self) -> Vec<ParticipantInfo> {
+        let mut participants: Vec<ParticipantInfo> = self.participants.values()
+            .map(|p| p.to_info(!self.hide_node_ids))
+            .collect();
+        participants.sort_by_key(|p| p.seat);
+        participants
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -270,12 +973,51 @@ impl Default for UserSettings {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Participant {
     id: String,
+    seat: u32,
     display_name: String,
     role: Role,
     connection_type: ConnectionType,
     is_muted: bool,
+    is_speaking: bool, // As last reported by the client
+    echo_muted: bool, // Muted by the server for feeding back the call; cleared when they unmute
     settings: UserSettings,
     avatar_url: Option<String>,
+    metadata: HashMap<String, String>,
+    capabilities: DecodeCapabilities,
+    audio_batch_size: u32, // Frames per outgoing audio message; 1 means unbatched
+    repacketize_batches: bool, // Merge batched frames into multi-frame Opus packets
+    resume_token: String, // Issued in JoinSuccess; lets a dropped client resume this participant
+    reconnecting_since: Option<u64>, // Set while the participant's socket is gone but within the grace window
+    output_epoch: u32, // See `WsAudioData::stream_epoch`
+    hand_raised: bool, // Asking to speak; cleared once they can
+    is_deafened: bool, // Receives no audio until they undeafen
+    force_muted: bool, // Muted by someone else; their audio is dropped until that's lifted
+    unmute_granted: bool, // Unmuted by someone else; see `Call::host_controls_mute`
+}
+
+impl Participant {
+    fn to_info(&self, show_node_id: bool) -> ParticipantInfo {
+        let node_id = match &self.connection_type {
+            ConnectionType::Node(node_id) if show_node_id => Some(node_id.clone()),
+            _ => None,
+        };
+        ParticipantInfo {
+            id: self.id.clone(),
+            seat: self.seat,
+            display_name: self.display_name.clone(),
+            node_id,
+            role: self.role.clone(),
+            is_muted: self.is_muted,
+            is_deafened: self.is_deafened,
+            is_speaking: self.is_speaking,
+            hand_raised: self.hand_raised,
+            unmute_granted: self.unmute_granted,
+            reconnecting: self.reconnecting_since.is_some(),
+            settings: self.settings.clone(),
+            avatar_url: self.avatar_url.clone(),
+            metadata: self.metadata.clone(),
+        }
+    }
 }
 
 #[hyperprocess(
@@ -305,6 +1047,7 @@ impl VoiceState {
             "yellow", "zebra", "acoustic", "bicycle", "chocolate", "diamond",
             "emerald", "fountain", "guitar", "helicopter", "illusion", "jasmine"
         ].into_iter().map(String::from).collect();
+        audio::set_node_decoder_limit(self.decoder_limit.unwrap_or(DEFAULT_NODE_DECODER_LIMIT));
 
         hyperware_process_lib::homepage::add_to_homepage(
             "Voice",
@@ -316,6 +1059,21 @@ impl VoiceState {
 
     #[http(method = "POST")]
     async fn create_call(&mut self, request: CreateCallReq) -> Result<CallInfo, String> {
+        run_housekeeping(self);
+        let custom_roles = request.custom_roles.unwrap_or_default();
+        let default_roles = [Some(&request.default_role), request.default_role_node.as_ref(), request.default_role_browser.as_ref()];
+        for role in default_roles.into_iter().flatten() {
+            if let Role::Custom(name) = role {
+                if !custom_roles.contains_key(name) {
+                    return Err(format!("Unknown role: {}", name));
+                }
+            }
+        }
+
+        let branding = validate_branding(request.branding.unwrap_or_default())?;
+
+        check_create_call_limits(self, &source().node)?;
+
         let call_id = generate_call_id(&self.word_dictionary);
 
         let call = Call {
@@ -327,22 +1085,47 @@ impl VoiceState {
                 .map_err(|e| e.to_string())?
                 .as_secs(),
             default_role: request.default_role.clone(),
+            default_role_node: request.default_role_node.clone(),
+            default_role_browser: request.default_role_browser.clone(),
             creator_id: None, // Will be set when creator joins
             host_id: None, // Will be set when first participant joins
+            reconnect_window_secs: request.reconnect_window_secs.unwrap_or(DEFAULT_RECONNECT_WINDOW_SECS),
+            next_seat: 0,
+            push_to_talk: request.push_to_talk.unwrap_or(false),
+            speakers_can_mute: request.speakers_can_mute.unwrap_or(false),
+            host_controls_mute: request.host_controls_mute.unwrap_or(false),
+            persistent: request.persistent.unwrap_or(false),
+            max_participants: request.max_participants.filter(|&max| max > 0),
+            hide_node_ids: request.hide_node_ids.unwrap_or(false),
+            reserved_slots: HashMap::new(),
+            floor_holder: None,
+            audio_config: request.audio_config.unwrap_or_default(),
+            max_chat_history: request.max_chat_history.unwrap_or(DEFAULT_MAX_CHAT_HISTORY).max(1),
+            custom_roles,
+            pinned_speakers: HashSet::new(),
+            audit_log: Vec::new(),
+            idle_speaker_timeout_secs: request.idle_speaker_timeout_secs.filter(|&secs| secs > 0),
+            screen_sharers: Vec::new(),
+            max_screen_shares: request.max_screen_shares.unwrap_or(1).max(1),
+            max_duration_secs: request.max_duration_secs.filter(|&secs| secs > 0),
+            pinned_message: None,
+            clock_origin_ms: current_timestamp().unwrap_or(0),
+            mix_clock_samples: None,
+            ending_soon_warnings_sent: 0,
+            last_silence_advisory: None,
+            bytes_sent: HashMap::new(),
+            playout_stats: HashMap::new(),
+            branding,
         };
 
-        let call_info = CallInfo {
-            id: call_id.clone(),
-            created_at: call.created_at,
-            participant_count: 0,
-            default_role: call.default_role.clone(),
-        };
+        let call_info = call.info();
 
         self.calls.insert(call_id.clone(), call);
         self.used_pleb_names.insert(call_id.clone(), Vec::new());
+        emit_analytics(self, &call_id, AnalyticsEventKind::CallCreated);
 
         // Serve the in-call UI at /call/<call-id>
-        let call_path = format!("/call/{}", call_id);
+        let call_path = call_path(&call_id);
         if let Err(e) = hyperware_app_common::get_server().unwrap().serve_ui(
             "ui-call",
             vec![&call_path],
@@ -357,115 +1140,296 @@ impl VoiceState {
 
     #[http(method = "POST")]
     async fn get_call_info(&mut self, call_id: String) -> Result<CallState, String> {
+        run_housekeeping(self);
+        let call_id = resolve_call_id(self, &call_id);
         let call = self.calls.get(&call_id)
-            .ok_or_else(|| "Call not found".to_string())?;
-
-        let participants: Vec<ParticipantInfo> = call.participants.values()
-            .map(|p| ParticipantInfo {
-                id: p.id.clone(),
-                display_name: p.display_name.clone(),
-                role: p.role.clone(),
-                is_muted: p.is_muted,
-                settings: p.settings.clone(),
-                avatar_url: p.avatar_url.clone(),
-            })
-            .collect();
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
+
+        let participants = call.participant_infos();
 
         let call_state = CallState {
-            call_info: CallInfo {
-                id: call.id.clone(),
-                created_at: call.created_at,
-                participant_count: call.participants.len() as u32,
-                default_role: call.default_role.clone(),
-            },
+            call_info: call.info(),
             participants,
             chat_history: call.chat_history.clone(),
+            pinned_message: call.pinned_message.clone(),
         };
 
         Ok(call_state)
     }
 
     #[http(method = "POST")]
-    async fn leave_call(&mut self, request: LeaveCallReq) -> Result<(), String> {
-        // First check if call exists
-        if !self.calls.contains_key(&request.call_id) {
-            return Err("Call not found".to_string());
+    async fn merge_calls(&mut self, request: MergeCallsReq) -> Result<CallInfo, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
         }
 
-        // Get host_id before any mutations
-        let host_id = self.calls.get(&request.call_id)
-            .and_then(|call| call.host_id.clone());
+        run_housekeeping(self);
+        let source_id = resolve_call_id(self, &request.source_call_id);
+        let dest_id = resolve_call_id(self, &request.dest_call_id);
+        merge_call_into(self, &source_id, &dest_id)?;
+        self.calls.get(&dest_id)
+            .map(Call::info)
+            .ok_or_else(|| "Call not found".to_string())
+    }
 
-        // Check if this is the host leaving
-        let is_host_leaving = host_id.as_ref() == Some(&request.participant_id);
+    #[http(method = "POST")]
+    async fn get_audit_log(&mut self, request: GetAuditLogReq) -> Result<Vec<AuditEntry>, String> {
+        let call_id = resolve_call_id(self, &request.call_id);
+        let call = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
 
-        // FIRST: Check if we should end the call (but don't remove participant yet if host)
-        let should_end_call = if let Some(call) = self.calls.get(&request.call_id) {
-            let would_be_empty = call.participants.len() <= 1 && call.participants.contains_key(&request.participant_id);
-            would_be_empty || is_host_leaving
-        } else {
-            false
-        };
+        let requester = call.participants.values()
+            .find(|p| p.resume_token == request.auth_token)
+            .ok_or_else(|| "Invalid auth token".to_string())?;
+        if !call.permissions(&requester.role).can_moderate() {
+            return Err("No permission to view the audit log".to_string());
+        }
 
-        // If not ending the call, remove the participant normally
-        if !should_end_call {
-            if let Some(call) = self.calls.get_mut(&request.call_id) {
-                call.participants.remove(&request.participant_id);
-            }
+        Ok(call.audit_log.clone())
+    }
 
-            // Clean up connection mappings for this participant
-            if let Some(channel_id) = self.participant_channels.remove(&request.participant_id) {
-                self.connections.remove(&channel_id);
-                if let Some(channels) = self.call_channels.get_mut(&request.call_id) {
-                    channels.remove(&channel_id);
-                }
-            }
-        }
+    // Moderators in a call that hides node ids can still see who is behind each participant
+    #[http(method = "POST")]
+    async fn get_participant_nodes(&mut self, request: GetParticipantNodesReq) -> Result<HashMap<String, String>, String> {
+        let call_id = resolve_call_id(self, &request.call_id);
+        let call = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
 
-        // Remove from audio processor
-        if let Some(processor) = self.audio_processors.get(&request.call_id) {
-            if let Ok(mut proc) = processor.lock() {
-                proc.remove_participant(&request.participant_id);
-            }
+        let requester = call.participants.values()
+            .find(|p| p.resume_token == request.auth_token)
+            .ok_or_else(|| "Invalid auth token".to_string())?;
+        if !call.permissions(&requester.role).can_moderate() {
+            return Err("No permission to view participant nodes".to_string());
         }
 
-        // Clean up output sequence numbers for this participant
-        self.participant_output_sequences.remove(&request.participant_id);
-
-        if should_end_call {
-            println!("Ending call {} - host leaving: {} or would be empty", request.call_id, is_host_leaving);
+        Ok(call.participants.values()
+            .filter_map(|p| match &p.connection_type {
+                ConnectionType::Node(node_id) => Some((p.id.clone(), node_id.clone())),
+                ConnectionType::Browser => None,
+            })
+            .collect())
+    }
 
-            // Log remaining participants before disconnecting
-            if let Some(channels) = self.call_channels.get(&request.call_id) {
-                println!("Call has {} channels to notify", channels.len());
-                for channel in channels {
-                    println!("  - Channel {} is still connected", channel);
-                }
-            }
+    // A shareable name for a call ("team-standup"), accepted anywhere a call id is joined or looked
+    // up on this node. Node-to-node join links still carry the real id, which names the host node.
+    #[http(method = "POST")]
+    async fn set_call_alias(&mut self, request: SetCallAliasReq) -> Result<(), String> {
+        let call_id = resolve_call_id(self, &request.call_id);
+        let call = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
 
-            // Disconnect all remaining WebSocket connections
-            disconnect_all_call_channels(&self, &request.call_id);
+        let requester = call.participants.values()
+            .find(|p| p.resume_token == request.auth_token)
+            .ok_or_else(|| "Invalid auth token".to_string())?;
+        if !call.permissions(&requester.role).can_moderate() {
+            return Err("No permission to set the call alias".to_string());
+        }
 
-            // Unserve the UI - now with participant already removed
-            let call_path = format!("/call/{}", request.call_id);
-            if let Err(e) = hyperware_app_common::get_server().unwrap().unserve_ui("ui-call", vec![&call_path]) {
-                println!("Failed to unserve UI for call {}: {:?}", request.call_id, e);
-            }
+        let alias = request.alias.trim().to_lowercase();
+        if alias.is_empty() {
+            remove_call_alias(self, &call_id);
+            return Ok(());
+        }
+        validate_call_alias(&alias)?;
+        if self.calls.contains_key(&alias) {
+            return Err("Alias is already a call id".to_string());
+        }
+        match self.call_aliases.get(&alias) {
+            Some(existing) if *existing == call_id => return Ok(()),
+            Some(_) => return Err("Alias is already taken".to_string()),
+            None => {}
+        }
 
-            // Clean up all state
-            self.calls.remove(&request.call_id);
-            self.used_pleb_names.remove(&request.call_id);
-            self.call_channels.remove(&request.call_id);
-            self.audio_processors.remove(&request.call_id);
-        } else {
-            // Notify remaining participants
-            let notification = WsServerMessage::ParticipantLeft { participant_id: request.participant_id.clone() };
-            broadcast_to_call(&self, &request.call_id, notification);
+        remove_call_alias(self, &call_id);
+        let alias_path = call_path(&alias);
+        if let Err(e) = hyperware_app_common::get_server().unwrap().serve_ui(
+            "ui-call",
+            vec![&alias_path],
+            HttpBindingConfig::default().authenticated(false)
+        ) {
+            println!("Failed to serve UI for alias {} of call {}: {:?}", alias, call_id, e);
+        }
+        self.call_aliases.insert(alias, call_id);
+        Ok(())
+    }
+
+    // Diagnostic: resolve a participant to their call when untangling connection mappings
+    #[http(method = "POST")]
+    async fn find_call_for_participant(&mut self, participant_id: String) -> Result<ParticipantLocation, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+
+        let (call_id, role) = find_participant_call(self, &participant_id)
+            .ok_or_else(|| "Participant not found".to_string())?;
+        Ok(ParticipantLocation { call_id, role })
+    }
+
+    // Operator escape hatch for a call stuck in a bad state: tear it down unconditionally
+    #[http(method = "POST")]
+    async fn force_end_call(&mut self, call_id: String) -> Result<(), String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        let call_id = resolve_call_id(self, &call_id);
+        let participant_count = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?
+            .participants.len();
+
+        println!("Force-ending call {} with {} participants", call_id, participant_count);
+        end_call(self, &call_id, CallEndReason::ForceEnded);
+        Ok(())
+    }
+
+    // Graceful stop: end every call so clients are told why instead of being left on dead sockets.
+    // Hit this before stopping or restarting the process.
+    #[http(method = "POST", path = "/shutdown")]
+    async fn shutdown(&mut self) -> Result<usize, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+
+        Ok(end_all_calls(self, CallEndReason::Shutdown))
+    }
+
+    // Bound the Opus decoders, one per speaking stream, that all calls on this node may hold between
+    // them. Past it, new speakers are refused while everyone can still join and listen.
+    #[http(method = "POST")]
+    async fn set_decoder_limit(&mut self, limit: usize) -> Result<DecoderUsage, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        self.decoder_limit = Some(limit);
+        audio::set_node_decoder_limit(limit);
+        Ok(DecoderUsage { live: audio::live_decoders(), limit })
+    }
+
+    // Tune how quickly dead clients are detected: joined channels are pinged every interval_ms and
+    // dropped after miss_threshold pings in a row go unanswered
+    #[http(method = "POST")]
+    async fn set_keepalive(&mut self, config: KeepaliveConfig) -> Result<(), String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        if config.interval_ms < HOUSEKEEPING_INTERVAL_MS {
+            return Err(format!("Ping interval must be at least {}ms", HOUSEKEEPING_INTERVAL_MS));
+        }
+        if config.miss_threshold == 0 {
+            return Err("Miss threshold must be at least 1".to_string());
+        }
+        self.keepalive = Some(config);
+        Ok(())
+    }
+
+    // Diagnostic: reconcile the connection maps now and see what was off. Housekeeping runs the
+    // same repair on its own.
+    #[http(method = "POST", path = "/debug/repair")]
+    async fn repair_connections(&mut self) -> Result<ConsistencyReport, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        Ok(repair_connection_state(self))
+    }
+
+    // Diagnostic: dump every connection mapping at once
+    #[http(method = "GET", path = "/debug/connections")]
+    async fn get_connection_map(&self) -> Result<ConnectionMap, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+
+        let call_channels = self.call_channels.iter()
+            .map(|(call_id, channels)| {
+                let mut channels: Vec<u32> = channels.iter().copied().collect();
+                channels.sort_unstable();
+                (call_id.clone(), channels)
+            })
+            .collect();
+        Ok(ConnectionMap {
+            connections: self.connections.clone(),
+            participant_channels: self.participant_channels.clone(),
+            call_channels,
+            participant_output_sequences: self.participant_output_sequences.clone(),
+        })
+    }
+
+    // Read-only: deliberately skips housekeeping so probing a call never changes it
+    #[http(method = "POST")]
+    async fn can_join(&mut self, call_id: String) -> Result<JoinCheck, String> {
+        let call_id = resolve_call_id(self, &call_id);
+        let Some(call) = self.calls.get(&call_id) else {
+            return Ok(JoinCheck::default());
+        };
+
+        // Calls have no lock, password or admission gate yet
+        Ok(JoinCheck {
+            exists: true,
+            is_full: call.is_full_for(None, current_timestamp().unwrap_or(0)),
+            ..JoinCheck::default()
+        })
+    }
+
+    // Diagnostic: codec, bandwidth and playout counters, which say who is in the call and how they
+    // connect, so only the local operator sees them
+    #[http(method = "POST")]
+    async fn get_call_stats(&mut self, call_id: String) -> Result<CallStats, String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        let call_id = resolve_call_id(self, &call_id);
+        let call = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
+
+        let audio = self.audio_processors.get(&call_id)
+            .map(|processor| lock_processor(processor).stats())
+            .unwrap_or_default();
+
+        Ok(CallStats {
+            audio,
+            bytes_sent: call.bytes_sent.clone(),
+            playout: call.playout_stats.clone(),
+        })
+    }
+
+    // Start a new accounting period for a call's outbound bandwidth, e.g. at a billing boundary
+    #[http(method = "POST")]
+    async fn reset_call_stats(&mut self, call_id: String) -> Result<(), String> {
+        if source().node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        let call_id = resolve_call_id(self, &call_id);
+        if !self.calls.contains_key(&call_id) {
+            return Err(call_missing_error(self, &call_id));
         }
 
+        if let Some(call) = self.calls.get_mut(&call_id) {
+            call.bytes_sent.clear();
+            call.playout_stats.clear();
+        }
         Ok(())
     }
 
+    #[http(method = "POST")]
+    async fn get_call_branding(&mut self, call_id: String) -> Result<CallBranding, String> {
+        let call_id = resolve_call_id(self, &call_id);
+        let call = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
+        Ok(call.branding.clone())
+    }
+
+    // Cheap alternative to get_call_info for UIs that only poll the headcount
+    #[http(method = "POST")]
+    async fn get_participant_count(&mut self, call_id: String) -> Result<ParticipantCount, String> {
+        let call_id = resolve_call_id(self, &call_id);
+        let call = self.calls.get(&call_id)
+            .ok_or_else(|| call_missing_error(self, &call_id))?;
+
+        Ok(ParticipantCount {
+            participant_count: call.participants.len(),
+            created_at: call.created_at,
+        })
+    }
+
     // Role updates are now handled via WebSocket messages
 
 
@@ -474,34 +1438,22 @@ impl VoiceState {
     #[http(method = "POST", path = "/start-node-handshake")]
     async fn start_node_handshake(&mut self, url: String) -> Result<String, String> {
         // Import the generated RPC function
-        use hyperware_process_lib::{Address, Request};
         use hyperware_app_common::send;
         use serde_json::json;
 
-        // Extract call ID from the URL
-        // Expected format: "https://<host>/voice:voice:sys/call/<call-id>"
-        let call_id = url
-            .split("/call/")
-            .nth(1)
-            .ok_or_else(|| "Invalid URL format: no call ID found".to_string())?
-            .split('?')  // Remove any query parameters
-            .next()
-            .unwrap_or("")
-            .to_string();
-
-        // Extract host node from the call ID
-        // Call ID format: "<host-node>-word1-word2-word3"
-        let host_node = call_id
-            .split('-')
-            .next()
+        // Expected format: a join link from `get_join_link`, e.g. "https://<host>/voice:voice:ware.hypr/call/<call-id>"
+        let call_id = call_id_from_join_link(&url)?;
+        let host_node = call_host_node(&call_id)
             .ok_or_else(|| "Invalid call ID format: no host node found".to_string())?;
 
         // Build the target address for the host node
         let target = Address::new(host_node, ("voice", "voice", "ware.hypr"));
 
         // Create the handshake request using our properly serialized type
+        // Our user is redirected straight into the call, so hold their seat until they get there
         let handshake_req = NodeHandshakeReq {
             call_id: call_id.clone(),
+            reserve_slot: Some(true),
         };
 
         // Send the node handshake request with proper serialization
@@ -520,12 +1472,32 @@ impl VoiceState {
         }
     }
 
+    // The one place join links are built; `start_node_handshake` parses them back with the matching
+    // helpers. The path is relative to this node's origin, which only the client knows.
+    #[http(method = "POST")]
+    async fn get_join_link(&mut self, call_id: String) -> Result<JoinLink, String> {
+        let call_id = resolve_call_id(self, &call_id);
+        if !self.calls.contains_key(&call_id) {
+            return Err(call_missing_error(self, &call_id));
+        }
+        let host_node = call_host_node(&call_id)
+            .ok_or_else(|| "Invalid call ID format: no host node found".to_string())?
+            .to_string();
+
+        Ok(JoinLink {
+            path: format!("/{}{}", our().process, call_path(&call_id)),
+            call_id,
+            host_node,
+        })
+    }
+
     #[local]
     #[remote]
-    async fn node_handshake(&mut self, request: NodeHandshakeReq) -> Result<NodeHandshakeResp, String> {
+    async fn node_handshake(&mut self, mut request: NodeHandshakeReq) -> Result<NodeHandshakeResp, String> {
+        request.call_id = resolve_call_id(self, &request.call_id);
         // Check if call exists
         if !self.calls.contains_key(&request.call_id) {
-            return Err("Call not found".to_string());
+            return Err(call_missing_error(self, &request.call_id));
         }
 
         // Generate auth token for this node
@@ -534,14 +1506,110 @@ impl VoiceState {
         // Get the requesting node's identity from the message source
         let node_id = source().node;
 
+        if request.reserve_slot.unwrap_or(false) {
+            let now = current_timestamp().unwrap_or(0);
+            if let Some(call) = self.calls.get_mut(&request.call_id) {
+                if call.max_participants.is_some() {
+                    if call.is_full_for(Some(&node_id), now) {
+                        return Err("Call is full".to_string());
+                    }
+                    call.reserved_slots.insert(node_id.clone(), now + SLOT_RESERVATION_TTL_MS);
+                }
+            }
+        }
+
         // Store the mapping
-        self.node_auth_tokens.insert(auth_token.clone(), node_id.clone());
+        self.node_auth_tokens.insert(auth_token.clone(), NodeAuthToken {
+            node_id,
+            call_id: request.call_id.clone(),
+        });
 
         Ok(NodeHandshakeResp {
             auth_token,
         })
     }
 
+    // Bots: a process on this node (transcription, recording, ...) joins without a browser. It gets
+    // an auth token from `node_handshake`, then `bot_join` gives it a virtual channel that stands in
+    // for a WebSocket: everything a browser would be pushed on it, mixed audio included, arrives at
+    // the bot's process as request bodies, and it talks back through `bot_message`.
+    #[local]
+    async fn bot_join(&mut self, request: BotJoinReq) -> Result<u32, String> {
+        run_housekeeping(self);
+        let bot = source();
+        if bot.node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+
+        let mut channel_id = rand::random::<u32>();
+        while self.connections.contains_key(&channel_id) || self.bot_channels.contains_key(&channel_id) {
+            channel_id = rand::random::<u32>();
+        }
+        self.bot_channels.insert(channel_id, bot);
+
+        handle_client_message(self, channel_id, WsClientMessage::JoinCall {
+            call_id: request.call_id,
+            auth_token: Some(request.auth_token),
+            display_name: request.display_name,
+            settings: None,
+            avatar_url: None,
+            resume_token: None,
+            capabilities: request.capabilities,
+            batch_frames: None,
+            repacketize: None,
+            last_seen_message_id: None,
+        });
+
+        // The join pushed its own error to the bot if it failed
+        if !self.connections.contains_key(&channel_id) {
+            self.bot_channels.remove(&channel_id);
+            return Err("Join failed".to_string());
+        }
+        Ok(channel_id)
+    }
+
+    #[local]
+    async fn bot_message(&mut self, request: BotMessageReq) -> Result<(), String> {
+        run_housekeeping(self);
+        if self.bot_channels.get(&request.channel_id) != Some(&source()) {
+            return Err("Not your channel".to_string());
+        }
+        handle_client_message(self, request.channel_id, request.message);
+        Ok(())
+    }
+
+    // Analytics: a process on this node (a dashboard, a logger writing to the VFS, ...) subscribes
+    // to call lifecycle events. Each arrives as a request whose body is one JSON line holding an
+    // `AnalyticsEvent`. Write-only: nothing is expected back, and the live WebSocket traffic is unaffected.
+    #[local]
+    async fn subscribe_analytics(&mut self, subscribe: bool) -> Result<(), String> {
+        let subscriber = source();
+        if subscriber.node != our().node {
+            return Err("Only available to the local node".to_string());
+        }
+        self.analytics_subscribers.retain(|existing| *existing != subscriber);
+        if subscribe {
+            self.analytics_subscribers.push(subscriber);
+        }
+        Ok(())
+    }
+
+    #[local]
+    async fn bot_leave(&mut self, channel_id: u32) -> Result<(), String> {
+        if self.bot_channels.get(&channel_id) != Some(&source()) {
+            return Err("Not your channel".to_string());
+        }
+        handle_disconnect(self, channel_id);
+        self.bot_channels.remove(&channel_id);
+        Ok(())
+    }
+
+    // Lets clients configure their encoder before joining instead of guessing
+    #[http(method = "GET", path = "/audio-caps")]
+    async fn get_audio_capabilities(&self) -> Result<AudioCapabilities, String> {
+        Ok(AudioCapabilities::current())
+    }
+
     #[http(method = "GET", path = "/host-settings")]
     async fn get_host_settings(&self) -> Result<UserSettings, String> {
         Ok(self.host_settings.clone())
@@ -556,6 +1624,10 @@ impl VoiceState {
     #[ws]
     fn websocket(&mut self, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
         println!("WebSocket event - channel_id: {}, type: {:?}", channel_id, message_type);
+        if !matches!(message_type, WsMessageType::Close) {
+            self.channel_last_seen.insert(channel_id, current_timestamp().unwrap_or(0));
+        }
+        run_housekeeping(self);
         match message_type {
             WsMessageType::Text => {
                 if let Ok(message) = String::from_utf8(blob.bytes.clone()) {
@@ -569,7 +1641,7 @@ impl VoiceState {
                         }
                         Err(e) => {
                             println!("Failed to parse WebSocket message: {}", e);
-                            send_error_to_channel(channel_id, "Invalid message format");
+                            send_error_to_channel(self, channel_id, "Invalid message format");
                         }
                     }
                 }
@@ -589,30 +1661,66 @@ impl VoiceState {
 
 // Helper functions for WebSocket handling
 fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientMessage) {
+    let mut outbox = Outbox::default();
+    dispatch_client_message(state, &mut outbox, channel_id, msg);
+    outbox.flush(state);
+}
+
+fn dispatch_client_message(state: &mut VoiceState, outbox: &mut Outbox, channel_id: u32, msg: WsClientMessage) {
     match msg {
-        WsClientMessage::JoinCall { call_id, auth_token, display_name, settings, avatar_url } => {
+        WsClientMessage::JoinCall { call_id, auth_token, display_name, settings, avatar_url, resume_token, capabilities, batch_frames, repacketize, last_seen_message_id } => {
+            let call_id = resolve_call_id(state, &call_id);
             // Check if call exists
             if !state.calls.contains_key(&call_id) {
-                send_error_to_channel(channel_id, "Call not found");
+                outbox.error(channel_id, &call_missing_error(state, &call_id));
+                return;
+            }
+
+            let requested_rate = capabilities.as_ref().and_then(|c| c.sample_rate);
+            if let Some(rate) = requested_rate.filter(|&rate| !audio::is_supported_sample_rate(rate)) {
+                outbox.error(channel_id, &format!("Unsupported output sample rate: {}", rate));
                 return;
             }
 
-            // Determine participant identity based on auth token
+            // A returning client resumes its existing participant instead of joining anew
+            if let Some(token) = resume_token {
+                let resuming = state.calls.get(&call_id)
+                    .and_then(|call| call.participants.values().find(|p| p.resume_token == token))
+                    .map(|p| p.id.clone());
+                if let Some(resuming) = resuming {
+                    if let Err(e) = check_join_rate(state, &format!("participant:{}", resuming)) {
+                        outbox.error(channel_id, &e);
+                        return;
+                    }
+                }
+                if resume_participant(state, outbox, channel_id, &call_id, &token, last_seen_message_id.as_deref()) {
+                    return;
+                }
+            }
+
+            // Determine participant identity based on auth token. Claiming host is only checked here;
+            // the claim itself happens below, in the same mutation that assigns the role.
+            let mut claims_host = false;
             let (participant_id, final_display_name, connection_type) = if let Some(token) = auth_token {
                 // Authenticated join - look up node ID from auth token
-                if let Some(node_id) = state.node_auth_tokens.get(&token) {
-                    // Use node ID as both participant ID and display name
-                    (node_id.clone(), display_name.unwrap_or_else(|| node_id.clone()), ConnectionType::Node(node_id.clone()))
+                if let Some(node_id) = state.node_auth_tokens.get(&token)
+                    .filter(|t| t.call_id == call_id)
+                    .map(|t| &t.node_id)
+                {
+                    let node_id = node_id.clone();
+                    let (participant_id, default_name) = node_identity(state, &call_id, &node_id);
+                    (participant_id, display_name.unwrap_or(default_name), ConnectionType::Node(node_id))
                 } else {
                     // Check if this is the host joining their own call
-                    let host_node = call_id.split('-').next().unwrap_or("");
                     let our_node = our().node;
 
-                    if host_node == our_node && state.calls.get(&call_id).map(|c| c.creator_id.is_none()).unwrap_or(false) {
+                    if call_host_node(&call_id) == Some(our_node.as_str()) && state.calls.get(&call_id).map(|c| c.creator_id.is_none()).unwrap_or(false) {
                         // This is the host joining their own call
-                        (our_node.clone(), display_name.unwrap_or_else(|| our_node.clone()), ConnectionType::Node(our_node))
+                        claims_host = true;
+                        let (participant_id, default_name) = node_identity(state, &call_id, &our_node);
+                        (participant_id, display_name.unwrap_or(default_name), ConnectionType::Node(our_node))
                     } else {
-                        send_error_to_channel(channel_id, "Invalid authentication token");
+                        outbox.error(channel_id, "Invalid authentication token");
                         return;
                     }
                 }
@@ -630,26 +1738,76 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
                 (participant_id, final_display_name, ConnectionType::Browser)
             };
 
+            // Anonymous browsers have nothing stable to key on until they hold a resume token
+            if let ConnectionType::Node(node_id) = &connection_type {
+                if let Err(e) = check_join_rate(state, &format!("node:{}", node_id)) {
+                    outbox.error(channel_id, &e);
+                    return;
+                }
+            }
+
+            let joining_node = match &connection_type {
+                ConnectionType::Node(node_id) => Some(node_id.clone()),
+                ConnectionType::Browser => None,
+            };
+            let is_full = state.calls.get(&call_id)
+                .is_some_and(|call| call.is_full_for(joining_node.as_deref(), current_timestamp().unwrap_or(0)));
+            if is_full {
+                outbox.error(channel_id, "Call is full");
+                return;
+            }
+
             // Now add the participant to the call
             if let Some(call) = state.calls.get_mut(&call_id) {
-                // Determine role and host
-                let role = if call.creator_id.is_none() {
-                    call.creator_id = Some(participant_id.clone());
-                    call.host_id = Some(participant_id.clone()); // First participant becomes host
-                    Role::Admin
-                } else {
-                    call.default_role.clone()
+                if let Some(node_id) = &joining_node {
+                    call.reserved_slots.remove(node_id);
+                }
+                // Determine role and host. Checking for and taking the creator slot is one step, so
+                // of two tokenless host joins exactly one becomes host and the other is refused.
+                let role = match &call.creator_id {
+                    None => {
+                        call.creator_id = Some(participant_id.clone());
+                        call.host_id = Some(participant_id.clone()); // First participant becomes host
+                        Role::Admin
+                    }
+                    Some(_) if claims_host => {
+                        outbox.error(channel_id, "Invalid authentication token");
+                        return;
+                    }
+                    Some(_) => call.default_role_for(&connection_type),
                 };
+                // A persistent room that ran empty is hostless until someone comes back
+                if call.host_id.is_none() {
+                    call.host_id = Some(participant_id.clone());
+                }
+
+                let seat = call.next_seat;
+                call.next_seat += 1;
 
                 // Create new participant - everyone starts muted
                 let participant = Participant {
                     id: participant_id.clone(),
+                    seat,
                     display_name: final_display_name.clone(),
                     role,
                     connection_type,
                     is_muted: true,
+                    is_speaking: false,
+                    echo_muted: false,
                     settings: settings.unwrap_or_default(),
-                    avatar_url: avatar_url.clone(),
+                    avatar_url: normalize_avatar_url(avatar_url),
+                    metadata: HashMap::new(),
+                    capabilities: capabilities.unwrap_or_default(),
+                    audio_batch_size: batch_frames.unwrap_or(1).clamp(1, MAX_AUDIO_BATCH_FRAMES),
+                    // Only Opus packets can be combined
+                    repacketize_batches: repacketize.unwrap_or(false) && call.audio_config.codec == AudioCodec::Opus,
+                    resume_token: generate_id(),
+                    reconnecting_since: None,
+                    output_epoch: 0,
+                    hand_raised: false,
+                    is_deafened: false,
+                    force_muted: false,
+                    unmute_granted: false,
                 };
 
                 // Add participant to call
@@ -666,36 +1824,17 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
                     .or_insert_with(HashSet::new)
                     .insert(channel_id);
 
-                // Generate auth token for future messages
-                let response_auth_token = generate_id();
-                // Note: We don't store this in node_auth_tokens since it's for WebSocket auth only
-
-                // Prepare response data
-                let participants: Vec<ParticipantInfo> = call.participants.values()
-                    .map(|p| ParticipantInfo {
-                        id: p.id.clone(),
-                        display_name: p.display_name.clone(),
-                        role: p.role.clone(),
-                        is_muted: p.is_muted,
-                        settings: p.settings.clone(),
-                        avatar_url: p.avatar_url.clone(),
-                    })
-                    .collect();
-
-                let chat_history = call.chat_history.clone();
-
                 // Add ALL participants to the audio processor so they can receive audio
-                let processor = state.audio_processors.entry(call_id.clone())
-                    .or_insert_with(|| Arc::new(Mutex::new(AudioProcessor::new())))
-                    .clone();
+                let processor = call_audio_processor(&mut state.audio_processors, call);
 
-                if let Ok(mut proc) = processor.lock() {
-                    if let Err(e) = proc.add_participant(participant_id.clone()) {
+                {
+                    let mut proc = lock_processor(&processor);
+                    if let Err(e) = add_to_audio_processor(&mut proc, &participant_id, &participant.capabilities) {
                         println!("Failed to add participant to audio processor on join: {}", e);
                     } else {
                         println!("Added participant {} to audio processor on join (role: {:?})", participant_id, participant.role);
                     }
-                };
+                }
 
                 // Reset output sequence for this participant
                 state.participant_output_sequences.insert(participant_id.clone(), 0);
@@ -707,30 +1846,24 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
                     println!("  {} -> {}", pid, seq);
                 }
 
-                // Send join success with host info
-                send_to_channel(channel_id, WsServerMessage::JoinSuccess {
-                    participant_id: participant_id.clone(),
-                    role: participant.role.clone(),
-                    participants,
-                    chat_history,
-                    auth_token: response_auth_token,
-                    host_id: call.host_id.clone(),
-                });
+                // Send join success with host info. The auth token in it doubles as the resume token for
+                // reconnecting within the grace window; it isn't stored in node_auth_tokens since it's
+                // for WebSocket auth only
+                if let Some(message) = call.join_success(&participant_id) {
+                    outbox.send(channel_id, message);
+                }
 
                 // Notify other participants
-                let participant_info = ParticipantInfo {
-                    id: participant.id,
-                    display_name: participant.display_name,
-                    role: participant.role,
-                    is_muted: participant.is_muted,
-                    settings: participant.settings,
-                    avatar_url: participant.avatar_url,
-                };
-                broadcast_to_call_except(state, &call_id, channel_id, WsServerMessage::ParticipantJoined(
+                let participant_info = participant.to_info(!call.hide_node_ids);
+                outbox.broadcast_except(&call_id, channel_id, WsServerMessage::ParticipantJoined(
                     WsParticipantJoined { participant: participant_info }
                 ));
+                emit_analytics(state, &call_id, AnalyticsEventKind::ParticipantJoined {
+                    participant_id,
+                    role: participant.role,
+                });
             } else {
-                send_error_to_channel(channel_id, "Call not found");
+                outbox.error(channel_id, "Call not found");
             }
             return;
         }
@@ -746,7 +1879,7 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
         },
         None => {
             println!("No connection found for channel_id: {}", channel_id);
-            send_error_to_channel(channel_id, "Not authenticated");
+            outbox.error(channel_id, "Not authenticated");
             return;
         }
     };
@@ -755,19 +1888,30 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
     let (call_id, participant_role) = match find_participant_call(state, &participant_id) {
         Some((cid, role)) => (cid, role),
         None => {
-            send_error_to_channel(channel_id, "Not in a call");
+            // The mapping outlived its call; drop it rather than erroring on every message
+            drop_stale_connection(state, channel_id, &participant_id);
             return;
         }
     };
+    let permissions = state.calls.get(&call_id)
+        .map(|call| call.permissions(&participant_role))
+        .unwrap_or_default();
 
     match msg {
         WsClientMessage::JoinCall { .. } => unreachable!(), // Already handled above
         WsClientMessage::Chat(content) => {
             // Check permission
-            if !can_chat(&participant_role) {
-                send_error_to_channel(channel_id, "No chat permission");
+            if !permissions.can_chat() {
+                outbox.error(channel_id, "No chat permission");
                 return;
             }
+            let content = match sanitize_chat_content(&content) {
+                Ok(content) => content,
+                Err(e) => {
+                    outbox.error(channel_id, &e);
+                    return;
+                }
+            };
 
             if let Some(call) = state.calls.get_mut(&call_id) {
                 if let Some(participant) = call.participants.get(&participant_id) {
@@ -777,12 +1921,14 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
                         sender_name: participant.display_name.clone(),
                         content,
                         timestamp: current_timestamp().unwrap_or(0),
+                        kind: ChatMessageKind::User,
                     };
 
                     call.chat_history.push(chat_msg.clone());
+                    call.trim_chat_history();
 
                     // Broadcast to all participants in the call
-                    broadcast_to_call(state, &call_id, WsServerMessage::Chat(WsChatMessage {
+                    outbox.broadcast(&call_id, WsServerMessage::Chat(WsChatMessage {
                         message: chat_msg
                     }));
                 }
@@ -790,10 +1936,32 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
         }
         WsClientMessage::Mute(is_muted) => {
             if let Some(call) = state.calls.get_mut(&call_id) {
+                let host_controls_mute = call.host_controls_mute;
                 if let Some(participant) = call.participants.get_mut(&participant_id) {
+                    let refusal = if is_muted {
+                        None
+                    } else if participant.force_muted {
+                        Some("Muted by a moderator")
+                    } else if host_controls_mute && !participant.unmute_granted && !permissions.can_moderate() {
+                        Some("Only a moderator can unmute you in this call")
+                    } else {
+                        None
+                    };
+                    if let Some(refusal) = refusal {
+                        // Clients unmute their mic before asking, so tell them to close it again
+                        outbox.error(channel_id, refusal);
+                        outbox.send(channel_id, WsServerMessage::ParticipantMuted(WsParticipantMuted {
+                            participant_id: participant_id.clone(),
+                            is_muted: true,
+                        }));
+                        return;
+                    }
                     participant.is_muted = is_muted;
+                    if !is_muted {
+                        participant.echo_muted = false;
+                    }
 
-                    broadcast_to_call(state, &call_id, WsServerMessage::ParticipantMuted(
+                    outbox.broadcast(&call_id, WsServerMessage::ParticipantMuted(
                         WsParticipantMuted {
                             participant_id: participant_id.clone(),
                             is_muted
@@ -802,67 +1970,241 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
                 }
             }
         }
-        WsClientMessage::AudioData { data, sample_rate: _, channels: _, sequence, timestamp: _ } => {
-            println!("AudioData received from {} (role: {:?}), input sequence: {:?}",
-                     participant_id, participant_role, sequence);
+        WsClientMessage::SetParticipantMuted { target_id, muted } => {
+            if !permissions.can_mute_others() {
+                outbox.error(channel_id, "No permission to mute others");
+                return;
+            }
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            let Some(target_role) = call.participants.get(&target_id).map(|p| p.role.clone()) else {
+                outbox.error(channel_id, "Target participant not found");
+                return;
+            };
+            // Co-hosts can't turn this on the moderators above them
+            if call.permissions(&target_role).can_moderate() && !permissions.can_moderate() {
+                outbox.error(channel_id, "Cannot mute a moderator");
+                return;
+            }
+
+            let Some(target) = call.participants.get_mut(&target_id) else { return };
+            target.force_muted = muted;
+            target.unmute_granted = !muted;
+            if muted {
+                target.is_muted = true;
+                println!("{} muted {} in call {}", participant_id, target_id, call_id);
+                outbox.broadcast(&call_id, WsServerMessage::ParticipantMuted(WsParticipantMuted {
+                    participant_id: target_id,
+                    is_muted: true,
+                }));
+            } else {
+                // Their mic is theirs to open; they stay muted until their own Mute(false)
+                println!("{} let {} unmute in call {}", participant_id, target_id, call_id);
+                if let Some(&target_channel) = state.participant_channels.get(&target_id) {
+                    outbox.send(target_channel, WsServerMessage::UnmuteAllowed);
+                }
+            }
+        }
+        WsClientMessage::SetDeafened { deafened } => {
+            let Some(participant) = state.calls.get_mut(&call_id)
+                .and_then(|call| call.participants.get_mut(&participant_id))
+            else {
+                return;
+            };
+            if participant.is_deafened == deafened {
+                return;
+            }
+            participant.is_deafened = deafened;
+
+            // Someone who can't hear the call shouldn't be talking into it either
+            let newly_muted = deafened && !participant.is_muted;
+            if newly_muted {
+                participant.is_muted = true;
+            }
+            if deafened {
+                state.audio_batches.remove(&participant_id);
+            }
 
-            // Check if the participant can speak
-            if !matches!(participant_role, Role::Speaker | Role::Admin) {
-                println!("Participant {} cannot speak (role: {:?})", participant_id, participant_role);
-                send_error_to_channel(channel_id, "No audio permission");
+            outbox.broadcast(&call_id, WsServerMessage::DeafenedUpdated {
+                participant_id: participant_id.clone(),
+                deafened,
+            });
+            if newly_muted {
+                outbox.broadcast(&call_id, WsServerMessage::ParticipantMuted(WsParticipantMuted {
+                    participant_id: participant_id.clone(),
+                    is_muted: true,
+                }));
+            }
+        }
+        WsClientMessage::AudioData { data, sample_rate, channels: _, sequence, timestamp: input_timestamp, stream } => {
+            let stream = stream.unwrap_or_else(|| DEFAULT_STREAM.to_string());
+            // Check if the participant can speak before doing any work on the frame
+            if !permissions.can_speak() {
+                let violations = state.audio_violations.entry(participant_id.clone()).or_insert(0);
+                *violations += 1;
+
+                // Tell the client once so it can stop sending; the rest are dropped quietly
+                if *violations == 1 {
+                    outbox.send(channel_id, WsServerMessage::AudioPermissionDenied);
+                }
+                if violations.is_power_of_two() {
+                    println!("Participant {} cannot speak (role: {:?}), dropped {} audio frames",
+                             participant_id, participant_role, violations);
+                }
                 return;
             }
 
+            if let Some(call) = state.calls.get(&call_id) {
+                // A mute made on the server (deafening, a moderator, echo) holds even while the
+                // client's mic is still live
+                if call.participants.get(&participant_id).is_some_and(|p| p.is_muted || p.echo_muted || p.force_muted) {
+                    return;
+                }
+                // Under host-controlled mute only moderators and those granted an unmute are heard
+                let needs_grant = call.host_controls_mute && !permissions.can_moderate();
+                if needs_grant && call.participants.get(&participant_id).is_some_and(|p| !p.unmute_granted) {
+                    return;
+                }
+                // In push-to-talk calls only the floor holder may be heard
+                if call.push_to_talk
+                    && call.floor_holder.as_ref() != Some(&participant_id)
+                    && !call.pinned_speakers.contains(&participant_id)
+                {
+                    println!("Dropping audio from {} - they don't hold the floor", participant_id);
+                    return;
+                }
+            }
+
+            println!("AudioData received from {} (role: {:?}), input sequence: {:?}",
+                     participant_id, participant_role, sequence);
+
             // Decode base64 to bytes
             let audio_bytes = base64_to_bytes(&data);
             println!("Decoded {} bytes of audio data from {}", audio_bytes.len(), participant_id);
 
             // Get or create audio processor for this call
-            let processor = state.audio_processors.entry(call_id.clone())
-                .or_insert_with(|| Arc::new(Mutex::new(AudioProcessor::new())))
-                .clone();
+            let processor = match state.calls.get(&call_id) {
+                Some(call) => call_audio_processor(&mut state.audio_processors, call),
+                None => return,
+            };
+            let capabilities = participant_capabilities(state, &call_id, &participant_id);
 
             // Process audio in the audio processor
-            let mixes_to_send = if let Ok(mut proc) = processor.lock() {
+            let mut echo_suspects = Vec::new();
+            let mixes_to_send = {
+                let mut proc = lock_processor(&processor);
                 println!("Got audio processor lock for call {}", call_id);
-                // Ensure participant is registered
+                // Speakers normally get registered on join; register late arrivals here. Non-speakers
+                // were already dropped above, so we never create an encoder/decoder on their behalf.
                 if !proc.has_participant(&participant_id) {
-                    if let Err(e) = proc.add_participant(participant_id.clone()) {
-                        println!("Failed to add participant to audio processor: {}", e);
+                    if let Err(e) = add_to_audio_processor(&mut proc, &participant_id, &capabilities) {
+                        println!("Failed to add participant {} to audio processor: {}", participant_id, e);
+                        outbox.error(channel_id, &format!("Audio setup failed: {}", e));
                         return;
                     }
                 }
 
-                // Decode Opus data
-                match proc.decode_audio(&participant_id, &audio_bytes) {
-                    Ok(decoded_audio) => {
-                        // Update participant's audio buffer
-                        proc.update_participant_audio(&participant_id, decoded_audio);
+                // Follow the client's capture rate; a no-op unless it changed. Refusals repeat every
+                // frame, so they're reported with the same backoff as missing permission.
+                if let Err(e) = proc.set_input_sample_rate(&participant_id, &stream, sample_rate) {
+                    let violations = state.audio_violations.entry(participant_id.clone()).or_insert(0);
+                    *violations += 1;
+                    if violations.is_power_of_two() {
+                        println!("Rejecting audio from {}: {}", participant_id, e);
+                        outbox.error(channel_id, &e);
+                    }
+                    return;
+                }
+
+                // Ogg input can carry several packets per message; each one gets its own mix
+                let packets = match proc.extract_packets(&participant_id, &stream, &audio_bytes) {
+                    Ok(packets) => packets,
+                    Err(e) => {
+                        println!("Failed to read audio from {}: {}", participant_id, e);
+                        outbox.error(channel_id, &format!("Audio decode error: {}", e));
+                        return;
+                    }
+                };
 
-                        // Create personalized outputs for all participants
-                        let mixes = proc.create_mix_minus_outputs();
-                        println!("Created {} mixes for call {}", mixes.len(), call_id);
+                let mut mixes_to_send = Vec::with_capacity(packets.len());
 
-                        // Return the mixes to send after releasing the lock
-                        Some(mixes)
+                // Fill in any frames lost since the last message, each getting its own mix
+                let missing = sequence.map_or(0, |sequence| proc.missing_frames(&participant_id, &stream, sequence));
+                for index in 0..missing {
+                    if let Err(e) = proc.conceal_frame(&participant_id, &stream, index) {
+                        println!("Failed to conceal lost audio from {}: {}", participant_id, e);
+                        break;
                     }
-                    Err(e) => {
-                        println!("Failed to decode audio from {}: {}", participant_id, e);
-                        // Send error to the participant but don't crash
-                        send_error_to_channel(channel_id, &format!("Audio decode error: {}", e));
-                        None
+                    mixes_to_send.push(proc.create_mix_minus_outputs());
+                }
+
+                for packet in packets {
+                    // Decode Opus data
+                    match proc.decode_audio(&participant_id, &stream, &packet) {
+                        Ok(decoded_audio) => {
+                            // Update this stream's audio buffer
+                            proc.update_participant_audio(&participant_id, &stream, decoded_audio);
+
+                            // Create personalized outputs for all participants
+                            let mixes = proc.create_mix_minus_outputs();
+                            println!("Created {} mixes for call {}", mixes.len(), call_id);
+
+                            // Keep the mixes to send after releasing the lock
+                            mixes_to_send.push(mixes);
+                        }
+                        Err(e) => {
+                            println!("Failed to decode audio from {}: {}", participant_id, e);
+                            // Send error to the participant but don't crash
+                            outbox.error(channel_id, &format!("Audio decode error: {}", e));
+                            break;
+                        }
                     }
                 }
-            } else {
-                println!("Failed to lock audio processor for call {}", call_id);
-                None
+
+                echo_suspects.extend(proc.take_echo_suspects());
+                for caption in proc.take_captions() {
+                    outbox.broadcast(&call_id, WsServerMessage::Caption {
+                        participant_id: caption.participant_id,
+                        text: caption.text,
+                        is_final: caption.is_final,
+                    });
+                }
+                mixes_to_send
             };
 
+            for suspect in &echo_suspects {
+                echo_mute(state, outbox, &call_id, suspect);
+            }
+
+            // With a single push-to-talk speaker every mix is just them, so their own timestamp
+            // still means something to listeners
+            let single_speaker = state.calls.get(&call_id).is_some_and(|call| {
+                call.push_to_talk
+                    && call.floor_holder.as_ref() == Some(&participant_id)
+                    && call.pinned_speakers.iter().all(|id| *id == participant_id)
+            });
+
             // Send the mixes after releasing all locks
-            if let Some(mixes) = mixes_to_send {
-                // Prepare all the messages first to avoid multiple mutable borrows
-                let messages_to_send: Vec<(u32, WsServerMessage)> = mixes.into_iter()
+            let tick_time = current_timestamp().unwrap_or(0);
+            for (index, mixes) in mixes_to_send.into_iter().enumerate() {
+                // Every target's mix from one tick shares a timestamp; each further packet unpacked
+                // from a single Ogg message (or concealed) moves the clock on by a frame
+                let frame_offset = index as u64 * FRAME_DURATION_MS;
+                let timestamp = state.calls.get_mut(&call_id)
+                    .map(|call| call.next_mix_timestamp(tick_time))
+                    .unwrap_or(0);
+                let source_timestamp = input_timestamp
+                    .filter(|_| single_speaker)
+                    .map(|input| input + frame_offset);
+
+                // Prepare all the frames first to avoid multiple mutable borrows
+                let frames_to_send: Vec<(u32, String, WsAudioData)> = mixes.into_iter()
                     .filter_map(|(target_id, mix_data)| {
+                        let deafened = state.calls.get(&call_id)
+                            .and_then(|call| call.participants.get(&target_id))
+                            .is_some_and(|p| p.is_deafened);
+                        if deafened {
+                            return None;
+                        }
                         println!("Sending {} bytes to participant {}", mix_data.len(), target_id);
 
                         if let Some(&target_channel_id) = state.participant_channels.get(&target_id) {
@@ -886,42 +2228,78 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
                                          current_seq, target_id, *seq);
                             }
 
-                            // Generate consistent timestamp based on sequence
-                            // 20ms per frame at 48kHz = 960 samples per sequence
-                            let timestamp = (current_seq as u64) * 20; // milliseconds
+                            // Report the rate and channel count this participant's mix was actually encoded at
+                            let target = state.calls.get(&call_id)
+                                .and_then(|call| call.participants.get(&target_id));
+                            let capabilities = target.map(|p| &p.capabilities);
+                            let output_rate = capabilities
+                                .and_then(|c| c.sample_rate)
+                                .unwrap_or(SAMPLE_RATE);
+                            let output_channels = if capabilities.is_some_and(|c| c.stereo) { 2 } else { 1 };
 
                             // Use consistent stream ID that the frontend expects
                             let stream_id = "audio-stream".to_string();
-                            let message = WsServerMessage::AudioData(WsAudioData {
+                            let frame = WsAudioData {
                                 participant_id: stream_id,
                                 data: bytes_to_base64(&mix_data),
                                 sequence: Some(current_seq),
                                 timestamp: Some(timestamp),
-                                sample_rate: Some(48000),
-                                channels: Some(1),
-                            });
+                                source_timestamp,
+                                stream_epoch: target.map(|p| p.output_epoch),
+                                sample_rate: Some(output_rate),
+                                channels: Some(output_channels),
+                            };
 
-                            Some((target_channel_id, message))
+                            Some((target_channel_id, target_id, frame))
                         } else {
                             None
                         }
                     })
                     .collect();
 
-                // Now send all the messages
-                for (channel_id, message) in messages_to_send {
-                    send_to_channel(channel_id, message);
+                // Now send all the frames, batching for participants who opted in
+                let now = current_timestamp().unwrap_or(0);
+                for (target_channel_id, target_id, frame) in frames_to_send {
+                    if let Some(call) = state.calls.get_mut(&call_id) {
+                        *call.bytes_sent.entry(target_id.clone()).or_default() += frame.data.len() as u64;
+                    }
+
+                    let batch_size = state.calls.get(&call_id)
+                        .and_then(|call| call.participants.get(&target_id))
+                        .map(|p| p.audio_batch_size)
+                        .unwrap_or(1);
+
+                    if batch_size <= 1 {
+                        outbox.send(target_channel_id, WsServerMessage::AudioData(frame));
+                        continue;
+                    }
+
+                    let batch = state.audio_batches.entry(target_id.clone())
+                        .or_insert_with(|| AudioBatch { frames: Vec::new(), started_at: now });
+                    batch.frames.push(frame);
+                    if batch.frames.len() >= batch_size as usize {
+                        flush_audio_batch(state, &target_id);
+                    }
                 }
+                flush_stale_audio_batches(state, now);
             }
         }
         WsClientMessage::UpdateRole { target_id, new_role } => {
-            // Check if requester has admin permission
-            if !matches!(participant_role, Role::Admin) {
-                send_error_to_channel(channel_id, "No permission to change roles");
+            // Check if requester has moderation permission
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to change roles");
                 return;
             }
 
             if let Some(call) = state.calls.get_mut(&call_id) {
+                if !call.has_role(&new_role) {
+                    outbox.error(channel_id, "Unknown role");
+                    return;
+                }
+                let keeps_speaking = call.permissions(&new_role).can_speak();
+                let could_speak = call.participants.get(&target_id)
+                    .is_some_and(|p| call.permissions(&p.role).can_speak());
+
                 // Check if target exists
                 if let Some(target_participant) = call.participants.get_mut(&target_id) {
                     let old_role = target_participant.role.clone();
@@ -931,75 +2309,412 @@ fn handle_client_message(state: &mut VoiceState, channel_id: u32, msg: WsClientM
 
                     // Log role change for debugging
                     println!("Role updated for participant {}: {:?} -> {:?}", target_id, old_role, new_role);
+                    call.audit(&participant_id, Some(&target_id), AuditAction::RoleChanged {
+                        from: old_role.clone(),
+                        to: new_role.clone(),
+                    });
+                    emit_analytics(state, &call_id, AnalyticsEventKind::RoleChanged {
+                        participant_id: target_id.clone(),
+                        from: old_role,
+                        to: new_role.clone(),
+                    });
 
                     // Broadcast role update to all participants
-                    broadcast_to_call(state, &call_id, WsServerMessage::RoleUpdated(
+                    outbox.broadcast(&call_id, WsServerMessage::RoleUpdated(
                         WsRoleUpdate {
                             participant_id: target_id.clone(),
                             new_role,
                         }
                     ));
+
+                    apply_role_change(state, outbox, &call_id, &target_id, could_speak, keeps_speaking);
+                    if keeps_speaking {
+                        lower_hands(state, outbox, &call_id, std::slice::from_ref(&target_id));
+                    }
                 } else {
-                    send_error_to_channel(channel_id, "Target participant not found");
+                    outbox.error(channel_id, "Target participant not found");
                 }
             }
         }
-        WsClientMessage::UpdateSettings(settings) => {
-            // Update participant's settings
-            if let Some(call) = state.calls.get_mut(&call_id) {
-                if let Some(participant) = call.participants.get_mut(&participant_id) {
-                    participant.settings = settings.clone();
+        WsClientMessage::UpdateRolesBulk { from_role, to_role } => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to change roles");
+                return;
+            }
 
-                    // Only notify the user themselves and the host (admin)
-                    // Send confirmation back to the user who updated their settings
-                    send_to_channel(channel_id, WsServerMessage::SettingsUpdated {
-                        participant_id: participant_id.clone(),
-                        settings: settings.clone(),
-                    });
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            if !call.has_role(&to_role) {
+                outbox.error(channel_id, "Unknown role");
+                return;
+            }
+            // Handing out moderation is deliberate, one participant at a time
+            if call.permissions(&to_role).can_moderate() {
+                outbox.error(channel_id, "Cannot bulk-promote to a moderating role");
+                return;
+            }
+            let could_speak = call.permissions(&from_role).can_speak();
+            let keeps_speaking = call.permissions(&to_role).can_speak();
+
+            let mut updated = Vec::new();
+            for participant in call.participants.values_mut() {
+                if participant.role == from_role {
+                    participant.role = to_role.clone();
+                    updated.push(participant.id.clone());
+                }
+            }
+            for target_id in &updated {
+                call.audit(&participant_id, Some(target_id), AuditAction::RoleChanged {
+                    from: from_role.clone(),
+                    to: to_role.clone(),
+                });
+            }
+            if updated.is_empty() {
+                return;
+            }
+            println!("Bulk role update in call {}: {} participants {:?} -> {:?}", call_id, updated.len(), from_role, to_role);
 
-                    // Also notify the host/admin if they're different from the user
-                    if let Some(host_id) = &call.host_id {
-                        if host_id != &participant_id {
-                            if let Some(host_channel) = state.participant_channels.get(host_id) {
-                                send_to_channel(*host_channel, WsServerMessage::SettingsUpdated {
-                                    participant_id: participant_id.clone(),
-                                    settings,
-                                });
-                            }
-                        }
+            // One broadcast for the whole batch rather than a RoleUpdated per participant
+            outbox.broadcast(&call_id, WsServerMessage::RolesUpdated {
+                participant_ids: updated.clone(),
+                new_role: to_role.clone(),
+            });
+
+            for target_id in &updated {
+                emit_analytics(state, &call_id, AnalyticsEventKind::RoleChanged {
+                    participant_id: target_id.clone(),
+                    from: from_role.clone(),
+                    to: to_role.clone(),
+                });
+                apply_role_change(state, outbox, &call_id, target_id, could_speak, keeps_speaking);
+            }
+            if keeps_speaking {
+                lower_hands(state, outbox, &call_id, &updated);
+            }
+        }
+        WsClientMessage::DefineRole { name, permissions: role_permissions } => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to define roles");
+                return;
+            }
+            if name.trim().is_empty() {
+                outbox.error(channel_id, "Role name cannot be empty");
+                return;
+            }
+
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            let role = Role::Custom(name.clone());
+            let could_speak = call.permissions(&role).can_speak();
+            call.custom_roles.insert(name.clone(), role_permissions);
+            call.audit(&participant_id, None, AuditAction::RoleDefined {
+                name: name.clone(),
+                permissions: role_permissions,
+            });
+            println!("Call {} defined role {}: {:?}", call_id, name, role_permissions);
+
+            // Redefining a role changes what everyone holding it may do, just as moving them to another
+            // role would
+            let holders: Vec<String> = call.participants.values()
+                .filter(|p| p.role == role)
+                .map(|p| p.id.clone())
+                .collect();
+
+            outbox.broadcast(&call_id, WsServerMessage::RoleDefined { name, permissions: role_permissions });
+
+            for holder in &holders {
+                apply_role_change(state, outbox, &call_id, holder, could_speak, role_permissions.can_speak());
+            }
+        }
+        WsClientMessage::PinSpeaker { target_id, pinned } => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to pin speakers");
+                return;
+            }
+
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            if !call.participants.contains_key(&target_id) {
+                outbox.error(channel_id, "Target participant not found");
+                return;
+            }
+            if pinned {
+                call.pinned_speakers.insert(target_id.clone());
+            } else {
+                call.pinned_speakers.remove(&target_id);
+            }
+            call.audit(&participant_id, Some(&target_id), AuditAction::SpeakerPinned { pinned });
+
+            if let Some(processor) = state.audio_processors.get(&call_id) {
+                lock_processor(processor).set_pinned(&target_id, pinned);
+            }
+
+            outbox.broadcast(&call_id, WsServerMessage::SpeakerPinned {
+                participant_id: target_id,
+                pinned,
+            });
+        }
+        WsClientMessage::SetPinnedMessage { content } => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to pin messages");
+                return;
+            }
+            let content = content.trim();
+            if content.chars().count() > MAX_PINNED_MESSAGE_CHARS {
+                outbox.error(channel_id, "Pinned message too long");
+                return;
+            }
+
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            call.pinned_message = Some(content.to_string()).filter(|content| !content.is_empty());
+            outbox.broadcast(&call_id, WsServerMessage::PinnedMessageUpdated {
+                content: call.pinned_message.clone(),
+            });
+        }
+        WsClientMessage::PlayChime { kind } => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to play chimes");
+                return;
+            }
+            println!("Participant {} rang a {:?} chime in call {}", participant_id, kind, call_id);
+            outbox.broadcast(&call_id, WsServerMessage::Chime { kind });
+        }
+        WsClientMessage::SetScreenShare { active } => {
+            if !active {
+                stop_screen_share(state, outbox, &call_id, &participant_id);
+                return;
+            }
+            if !permissions.can_share() {
+                outbox.error(channel_id, "No screen share permission");
+                return;
+            }
+
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            if call.screen_sharers.contains(&participant_id) {
+                return;
+            }
+            if call.screen_sharers.len() >= call.max_screen_shares {
+                outbox.error(channel_id, "Someone else is already sharing their screen");
+                return;
+            }
+            call.screen_sharers.push(participant_id.clone());
+            outbox.broadcast(&call_id, WsServerMessage::ScreenShareUpdated {
+                participant_id: participant_id.clone(),
+                active: true,
+            });
+        }
+        WsClientMessage::UpdateSettings(settings) => {
+            // Update participant's settings
+            if let Some(call) = state.calls.get_mut(&call_id) {
+                if let Some(participant) = call.participants.get_mut(&participant_id) {
+                    participant.settings = settings.clone();
+
+                    // Only notify the user themselves and the moderators. A moderator updating their own
+                    // settings gets the moderator copy rather than a separate confirmation.
+                    let message = WsServerMessage::SettingsUpdated {
+                        participant_id: participant_id.clone(),
+                        settings,
+                    };
+                    if !permissions.can_moderate() {
+                        outbox.send(channel_id, message.clone());
                     }
+                    outbox.broadcast_to_role(&call_id, |_, permissions| permissions.can_moderate(), message);
                 } else {
-                    send_error_to_channel(channel_id, "Participant not found");
+                    outbox.error(channel_id, "Participant not found");
                 }
             }
         }
         WsClientMessage::UpdateSpeakingState { is_speaking } => {
             // Only allow speakers and admins to update speaking state
-            if matches!(participant_role, Role::Speaker | Role::Admin) {
+            if permissions.can_speak() {
+                if let Some(participant) = state.calls.get_mut(&call_id)
+                    .and_then(|call| call.participants.get_mut(&participant_id))
+                {
+                    participant.is_speaking = is_speaking;
+                }
+
                 // Broadcast speaking state to all participants
-                broadcast_to_call(state, &call_id, WsServerMessage::SpeakingStateUpdated {
+                outbox.broadcast(&call_id, WsServerMessage::SpeakingStateUpdated {
                     participant_id: participant_id.clone(),
                     is_speaking,
                 });
             }
         }
+        WsClientMessage::RaiseHand(raised) => {
+            let Some(participant) = state.calls.get_mut(&call_id)
+                .and_then(|call| call.participants.get_mut(&participant_id))
+            else {
+                return;
+            };
+            if participant.hand_raised == raised {
+                return;
+            }
+            participant.hand_raised = raised;
+            outbox.broadcast(&call_id, WsServerMessage::HandRaised {
+                participant_id: participant_id.clone(),
+                raised,
+            });
+        }
+        WsClientMessage::SetMutePolicy { host_controls_mute } => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to change the mute policy");
+                return;
+            }
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            if call.host_controls_mute == host_controls_mute {
+                return;
+            }
+            call.host_controls_mute = host_controls_mute;
+            println!("{} set host-controlled mute to {} in call {}", participant_id, host_controls_mute, call_id);
+            outbox.broadcast(&call_id, WsServerMessage::MutePolicyUpdated { host_controls_mute });
+
+            if host_controls_mute {
+                let silenced: Vec<String> = call.participants.values()
+                    .filter(|p| !p.is_muted && !p.unmute_granted && !call.permissions(&p.role).can_moderate())
+                    .map(|p| p.id.clone())
+                    .collect();
+                for target_id in silenced {
+                    if let Some(target) = call.participants.get_mut(&target_id) {
+                        target.is_muted = true;
+                    }
+                    outbox.broadcast(&call_id, WsServerMessage::ParticipantMuted(WsParticipantMuted {
+                        participant_id: target_id,
+                        is_muted: true,
+                    }));
+                }
+            }
+        }
+        WsClientMessage::LowerAllHands => {
+            if !permissions.can_moderate() {
+                outbox.error(channel_id, "No permission to lower hands");
+                return;
+            }
+            let Some(call) = state.calls.get(&call_id) else { return };
+            let participant_ids: Vec<String> = call.participants.keys().cloned().collect();
+            lower_hands(state, outbox, &call_id, &participant_ids);
+        }
         WsClientMessage::UpdateAvatar { avatar_url } => {
-            // Update participant's avatar
+            // Update participant's avatar. Removal is broadcast like any other change; whether
+            // avatars are shown at all is each viewer's `show_avatars` setting
+            let avatar_url = normalize_avatar_url(avatar_url);
             if let Some(call) = state.calls.get_mut(&call_id) {
                 if let Some(participant) = call.participants.get_mut(&participant_id) {
                     participant.avatar_url = avatar_url.clone();
 
                     // Broadcast avatar update to all participants
-                    broadcast_to_call(state, &call_id, WsServerMessage::AvatarUpdated {
+                    outbox.broadcast(&call_id, WsServerMessage::AvatarUpdated {
                         participant_id: participant_id.clone(),
                         avatar_url: avatar_url.clone(),
                     });
                 }
             }
         }
+        WsClientMessage::SetMetadata { key, value } => {
+            if key.is_empty() || key.chars().count() > MAX_METADATA_KEY_CHARS {
+                outbox.error(channel_id, "Invalid metadata key");
+                return;
+            }
+            if value.chars().count() > MAX_METADATA_VALUE_CHARS {
+                outbox.error(channel_id, "Metadata value too long");
+                return;
+            }
+
+            let Some(participant) = state.calls.get_mut(&call_id)
+                .and_then(|call| call.participants.get_mut(&participant_id)) else { return };
+            if value.is_empty() {
+                participant.metadata.remove(&key);
+            } else {
+                if !participant.metadata.contains_key(&key) && participant.metadata.len() >= MAX_METADATA_KEYS {
+                    outbox.error(channel_id, "Too many metadata keys");
+                    return;
+                }
+                participant.metadata.insert(key.clone(), value.clone());
+            }
+
+            outbox.broadcast(&call_id, WsServerMessage::MetadataUpdated {
+                participant_id: participant_id.clone(),
+                key,
+                value: Some(value).filter(|value| !value.is_empty()),
+            });
+        }
+        WsClientMessage::RequestFloor => {
+            if !permissions.can_speak() {
+                outbox.error(channel_id, "No audio permission");
+                return;
+            }
+
+            if let Some(call) = state.calls.get_mut(&call_id) {
+                if !call.push_to_talk {
+                    outbox.error(channel_id, "Call is not in push-to-talk mode");
+                    return;
+                }
+
+                match &call.floor_holder {
+                    Some(holder) if holder != &participant_id => {
+                        outbox.error(channel_id, "Floor is taken");
+                    }
+                    _ => {
+                        call.floor_holder = Some(participant_id.clone());
+                        set_exclusive_speaker(state, &call_id, Some(participant_id.clone()));
+                        outbox.broadcast(&call_id, WsServerMessage::FloorGranted {
+                            participant_id: participant_id.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        WsClientMessage::ReleaseFloor => {
+            let holder = state.calls.get(&call_id).and_then(|call| call.floor_holder.clone());
+            match holder {
+                // Moderators may take the floor away from someone else
+                Some(holder) if holder == participant_id || permissions.can_moderate() => {
+                    if holder != participant_id {
+                        if let Some(call) = state.calls.get_mut(&call_id) {
+                            call.audit(&participant_id, Some(&holder), AuditAction::FloorRevoked);
+                        }
+                    }
+                    release_floor(state, outbox, &call_id, &holder);
+                }
+                Some(_) => {
+                    outbox.error(channel_id, "You don't hold the floor");
+                }
+                None => {}
+            }
+        }
+        WsClientMessage::Resync(last_seen_message_id) => {
+            if let Some(call) = state.calls.get(&call_id) {
+                outbox.send(channel_id, call.full_state_since(last_seen_message_id.as_deref()));
+            }
+        }
+        WsClientMessage::PlayoutStats { buffer_ms, underruns, late_frames } => {
+            let now = current_timestamp().unwrap_or(0);
+            let Some(call) = state.calls.get_mut(&call_id) else { return };
+            let summary = call.playout_stats.entry(participant_id.clone()).or_default();
+            if summary.reports > 0 && now.saturating_sub(summary.last_report_at) < MIN_PLAYOUT_REPORT_INTERVAL_MS {
+                return;
+            }
+            summary.reports += 1;
+            summary.last_buffer_ms = buffer_ms;
+            summary.mean_buffer_ms += (buffer_ms as f64 - summary.mean_buffer_ms) / summary.reports as f64;
+            summary.max_buffer_ms = summary.max_buffer_ms.max(buffer_ms);
+            summary.underruns += underruns as u64;
+            summary.late_frames += late_frames as u64;
+            summary.last_report_at = now;
+        }
         WsClientMessage::Heartbeat => {
             // Keep connection alive - no action needed
         }
+        WsClientMessage::Leave => {
+            // Unmapped before the close goes out, so the Close event that follows is a no-op rather
+            // than a second departure, and a repeated Leave is refused as unauthenticated
+            println!("Participant {} left call {}", participant_id, call_id);
+            remove_participant_from_call(state, &call_id, &participant_id);
+            state.channel_last_seen.remove(&channel_id);
+            state.channel_pings.remove(&channel_id);
+            close_channel(state, channel_id, WS_CLOSE_NORMAL, "Left the call");
+        }
+        WsClientMessage::Pong => {
+            if let Some(pings) = state.channel_pings.get_mut(&channel_id) {
+                pings.unanswered = 0;
+            }
+        }
     }
 
 }
@@ -1012,6 +2727,68 @@ fn generate_call_id(dictionary: &[String]) -> String {
     format!("{}-{}", our().node, words.join("-"))
 }
 
+/// Where a call's UI is served, relative to the process.
+fn call_path(call_id: &str) -> String {
+    format!("/call/{}", call_id)
+}
+
+/// The call an id or vanity alias refers to. Aliases match however they're cased or padded, as
+/// `set_call_alias` stores them normalized; ids that aren't aliases come back unchanged.
+fn resolve_call_id(state: &VoiceState, call_id: &str) -> String {
+    state.call_aliases.get(&call_id.trim().to_lowercase())
+        .cloned()
+        .unwrap_or_else(|| call_id.to_string())
+}
+
+/// Aliases are lowercase words joined by single dashes, and must not parse as a generated id
+/// ("<host-node>-word1-word2-word3"), which would make join links ambiguous.
+fn validate_call_alias(alias: &str) -> Result<(), String> {
+    if alias.len() > MAX_CALL_ALIAS_CHARS {
+        return Err(format!("Alias must be at most {} characters", MAX_CALL_ALIAS_CHARS));
+    }
+    let valid_words = alias.split('-').all(|word| {
+        !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    });
+    if !valid_words {
+        return Err("Alias may only contain letters, digits and single dashes between words".to_string());
+    }
+    if call_host_node(alias).is_some() {
+        return Err("Alias looks like a call id; use at most three words".to_string());
+    }
+    Ok(())
+}
+
+/// Drop a call's alias, if it has one, and stop serving the UI under it.
+fn remove_call_alias(state: &mut VoiceState, call_id: &str) {
+    let aliases: Vec<String> = state.call_aliases.iter()
+        .filter(|(_, target)| *target == call_id)
+        .map(|(alias, _)| alias.clone())
+        .collect();
+    for alias in aliases {
+        state.call_aliases.remove(&alias);
+        unserve_call_ui(&call_path(&alias));
+    }
+}
+
+/// The call ID out of a join link, ignoring any query string or fragment.
+fn call_id_from_join_link(url: &str) -> Result<String, String> {
+    let call_id = url
+        .split_once("/call/")
+        .map(|(_, rest)| rest)
+        .and_then(|rest| rest.split(['?', '#', '/']).next())
+        .filter(|call_id| !call_id.is_empty())
+        .ok_or_else(|| "Invalid URL format: no call ID found".to_string())?;
+    Ok(call_id.to_string())
+}
+
+/// The node hosting a call, from its ID: "<host-node>-word1-word2-word3". Node names may contain
+/// dashes themselves, so the words are taken off the end.
+fn call_host_node(call_id: &str) -> Option<&str> {
+    let mut parts = call_id.rsplitn(4, '-');
+    let _words = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next().filter(|node| !node.is_empty())
+}
+
 fn generate_pleb_name_for_call(state: &mut VoiceState, call_id: &str) -> String {
     let dictionary = state.word_dictionary.clone();
     let used_names = state.used_pleb_names.entry(call_id.to_string()).or_insert_with(Vec::new);
@@ -1043,12 +2820,146 @@ fn current_timestamp() -> Result<u64, String> {
         .map_err(|e| e.to_string())
 }
 
-fn can_chat(role: &Role) -> bool {
-    matches!(role, Role::Chatter | Role::Speaker | Role::Admin)
+/// Lock a call's audio processor. The process is single-threaded so `lock` never blocks; it only
+/// fails if a previous holder panicked. Rather than dropping the frame we recover the processor
+/// and count the failure so it shows up in call stats.
+fn lock_processor(processor: &Mutex<AudioProcessor>) -> MutexGuard<'_, AudioProcessor> {
+    processor.lock().unwrap_or_else(|poisoned| {
+        println!("Audio processor lock was poisoned, recovering");
+        let mut proc = poisoned.into_inner();
+        proc.record_lock_failure();
+        proc
+    })
+}
+
+/// Get or create the audio processor for a call, configured from the call's audio settings.
+fn call_audio_processor(processors: &mut HashMap<String, Arc<Mutex<AudioProcessor>>>, call: &Call) -> Arc<Mutex<AudioProcessor>> {
+    processors.entry(call.id.clone())
+        .or_insert_with(|| {
+            let mut processor = AudioProcessor::new(call.audio_config.clone());
+            if call.audio_config.captions {
+                processor.set_transcription_sink(Some(Box::new(SegmentSink::default())));
+            }
+            Arc::new(Mutex::new(processor))
+        })
+        .clone()
+}
+
+/// Register a participant with a call's audio processor, encoding their mix to suit what they can decode.
+fn add_to_audio_processor(proc: &mut AudioProcessor, participant_id: &str, capabilities: &DecodeCapabilities) -> Result<(), String> {
+    proc.add_participant(participant_id.to_string())?;
+    proc.set_output_profile(participant_id, capabilities.output_profile())
+}
+
+fn participant_capabilities(state: &VoiceState, call_id: &str, participant_id: &str) -> DecodeCapabilities {
+    state.calls.get(call_id)
+        .and_then(|call| call.participants.get(participant_id))
+        .map(|p| p.capabilities.clone())
+        .unwrap_or_default()
+}
+
+/// Release the push-to-talk floor if `participant_id` holds it.
+fn release_floor(state: &mut VoiceState, outbox: &mut Outbox, call_id: &str, participant_id: &str) {
+    let released = match state.calls.get_mut(call_id) {
+        Some(call) if call.floor_holder.as_deref() == Some(participant_id) => {
+            call.floor_holder = None;
+            true
+        }
+        _ => false,
+    };
+
+    if released {
+        println!("Floor released by {} in call {}", participant_id, call_id);
+        set_exclusive_speaker(state, call_id, None);
+        outbox.broadcast(call_id, WsServerMessage::FloorReleased);
+    }
+}
+
+/// End `participant_id`'s screen share, if they have one.
+fn stop_screen_share(state: &mut VoiceState, outbox: &mut Outbox, call_id: &str, participant_id: &str) {
+    let Some(call) = state.calls.get_mut(call_id) else { return };
+    let was_sharing = call.screen_sharers.iter().any(|id| id == participant_id);
+    if was_sharing {
+        call.screen_sharers.retain(|id| id != participant_id);
+        outbox.broadcast(call_id, WsServerMessage::ScreenShareUpdated {
+            participant_id: participant_id.to_string(),
+            active: false,
+        });
+    }
+}
+
+/// Bring someone in line with the role they now hold: audio violations counted afresh, fresh codecs
+/// and a heads-up if speaking was granted or taken away, and the floor or a screen share taken away
+/// if the role no longer allows them.
+fn apply_role_change(state: &mut VoiceState, outbox: &mut Outbox, call_id: &str, participant_id: &str, could_speak: bool, can_speak: bool) {
+    state.audio_violations.remove(participant_id);
+    if could_speak != can_speak {
+        reset_codecs(state, call_id, participant_id, can_speak);
+        notify_speaking_enabled(state, outbox, participant_id, can_speak);
+    }
+    if can_speak {
+        mark_speaker_active(state, call_id, participant_id);
+    }
+    revoke_lost_privileges(state, outbox, call_id, participant_id);
+}
+
+/// Take the floor and any screen share away from someone whose role no longer allows them.
+fn revoke_lost_privileges(state: &mut VoiceState, outbox: &mut Outbox, call_id: &str, participant_id: &str) {
+    let permissions = state.calls.get(call_id)
+        .and_then(|call| call.participants.get(participant_id).map(|p| call.permissions(&p.role)))
+        .unwrap_or_default();
+    if !permissions.can_speak() {
+        release_floor(state, outbox, call_id, participant_id);
+    }
+    if !permissions.can_share() {
+        stop_screen_share(state, outbox, call_id, participant_id);
+    }
+}
+
+/// Fresh codec state for someone whose role just granted or took away speaking, if the call wants
+/// it. A participant who can no longer speak doesn't keep a decoder around.
+fn reset_codecs(state: &VoiceState, call_id: &str, participant_id: &str, can_speak: bool) {
+    let Some(call) = state.calls.get(call_id) else { return };
+    if !call.audio_config.reset_codecs_on_role_change {
+        return;
+    }
+    if let Some(processor) = state.audio_processors.get(call_id) {
+        let mut proc = lock_processor(processor);
+        if let Err(e) = proc.reset_codecs(participant_id) {
+            println!("Failed to reset codecs for {}: {}", participant_id, e);
+        }
+        if !can_speak {
+            proc.release_decoder(participant_id);
+        }
+    }
+}
+
+/// Mute someone whose mic is picking up the call, until they unmute themselves.
+fn echo_mute(state: &mut VoiceState, outbox: &mut Outbox, call_id: &str, participant_id: &str) {
+    let Some(participant) = state.calls.get_mut(call_id)
+        .and_then(|call| call.participants.get_mut(participant_id)) else { return };
+    participant.is_muted = true;
+    participant.echo_muted = true;
+
+    if let Some(&channel_id) = state.participant_channels.get(participant_id) {
+        outbox.send(channel_id, WsServerMessage::EchoDetected);
+    }
+    outbox.broadcast(call_id, WsServerMessage::ParticipantMuted(WsParticipantMuted {
+        participant_id: participant_id.to_string(),
+        is_muted: true,
+    }));
+}
+
+fn set_exclusive_speaker(state: &VoiceState, call_id: &str, participant_id: Option<String>) {
+    if let Some(processor) = state.audio_processors.get(call_id) {
+        lock_processor(processor).set_exclusive_speaker(participant_id);
+    }
 }
 
 fn handle_disconnect(state: &mut VoiceState, channel_id: u32) {
     println!("Handling disconnect for channel_id: {}", channel_id);
+    state.channel_last_seen.remove(&channel_id);
+    state.channel_pings.remove(&channel_id);
     if let Some(participant_id) = state.connections.remove(&channel_id) {
         println!("Removed connection for participant: {}", participant_id);
         state.participant_channels.remove(&participant_id);
@@ -1057,148 +2968,1155 @@ fn handle_disconnect(state: &mut VoiceState, channel_id: u32) {
         let call_info = state.calls.iter()
             .find_map(|(cid, call)| {
                 if call.participants.contains_key(&participant_id) {
-                    Some((cid.clone(), call.host_id.clone()))
+                    Some((cid.clone(), call.reconnect_window_secs))
                 } else {
                     None
                 }
             });
 
-        if let Some((call_id, host_id)) = call_info {
-            // Remove participant from audio processor
-            if let Some(processor) = state.audio_processors.get(&call_id) {
-                if let Ok(mut proc) = processor.lock() {
-                    proc.remove_participant(&participant_id);
-                }
-            }
-
-            // Clean up output sequence numbers for this participant
-            state.participant_output_sequences.remove(&participant_id);
-
+        if let Some((call_id, reconnect_window_secs)) = call_info {
             // Remove this channel from the call's channel set
             if let Some(channels) = state.call_channels.get_mut(&call_id) {
                 channels.remove(&channel_id);
             }
 
-            // Check if this participant is the host
-            let is_host_leaving = host_id.as_ref() == Some(&participant_id);
-
-            // Determine if we should end the call
-            let should_end_call = {
-                if let Some(call) = state.calls.get_mut(&call_id) {
-                    call.participants.remove(&participant_id);
-                    let is_empty = call.participants.is_empty();
-                    is_empty || is_host_leaving
-                } else {
-                    false
-                }
-            };
-
-            if should_end_call {
-                println!("Ending call {} - host leaving: {}", call_id, is_host_leaving);
-
-                // Disconnect all remaining participants
-                disconnect_all_call_channels(state, &call_id);
-
-                // Unserve the UI
-                let call_path = format!("/call/{}", call_id);
-                if let Err(e) = hyperware_app_common::get_server().unwrap().unserve_ui("ui-call", vec![&call_path]) {
-                    println!("Failed to unserve UI for call {}: {:?}", call_id, e);
-                }
-
-                // Clean up call state - this must happen OUTSIDE the borrow scope
-                state.calls.remove(&call_id);
-                state.used_pleb_names.remove(&call_id);
-                state.call_channels.remove(&call_id);
-                state.audio_processors.remove(&call_id);
+            if reconnect_window_secs > 0 {
+                // Keep the participant around in case this was just a network blip
+                mark_reconnecting(state, &call_id, &participant_id);
             } else {
-                // Just notify remaining participants
-                let notification = WsServerMessage::ParticipantLeft { participant_id: participant_id.clone() };
-                broadcast_to_call(state, &call_id, notification);
+                remove_participant_from_call(state, &call_id, &participant_id);
             }
         }
     }
     println!("Done disconnecting {channel_id}");
 }
 
-fn find_participant_call(state: &VoiceState, participant_id: &str) -> Option<(String, Role)> {
-    for (call_id, call) in &state.calls {
-        if let Some(participant) = call.participants.get(participant_id) {
-            return Some((call_id.clone(), participant.role.clone()));
-        }
+fn mark_reconnecting(state: &mut VoiceState, call_id: &str, participant_id: &str) {
+    println!("Participant {} dropped from call {}, waiting for reconnect", participant_id, call_id);
+    let mut outbox = Outbox::default();
+    release_floor(state, &mut outbox, call_id, participant_id);
+    stop_screen_share(state, &mut outbox, call_id, participant_id);
+
+    if let Some(participant) = state.calls.get_mut(call_id)
+        .and_then(|call| call.participants.get_mut(participant_id))
+    {
+        participant.reconnecting_since = Some(current_timestamp().unwrap_or(0));
+        outbox.broadcast(call_id, WsServerMessage::ParticipantReconnecting {
+            participant_id: participant_id.to_string(),
+            reconnecting: true,
+        });
     }
-    None
+    outbox.flush(state);
+
+    // Exclude them from audio until they come back
+    if let Some(processor) = state.audio_processors.get(call_id) {
+        lock_processor(processor).remove_participant(participant_id);
+    }
+    // Their output sequence is kept so it carries on where it left off if they resume
+    state.audio_batches.remove(participant_id);
 }
 
-fn broadcast_to_call(state: &VoiceState, call_id: &str, message: WsServerMessage) {
-    if let Some(call) = state.calls.get(call_id) {
-        let message_json = serde_json::to_string(&message).unwrap_or_default();
-        let message_bytes = message_json.into_bytes();
+/// Rebind a returning participant to a new channel using the token issued in `JoinSuccess`.
+/// Returns false if the token doesn't belong to anyone in the call.
+fn resume_participant(state: &mut VoiceState, outbox: &mut Outbox, channel_id: u32, call_id: &str, resume_token: &str, last_seen_message_id: Option<&str>) -> bool {
+    let participant_id = match state.calls.get(call_id)
+        .and_then(|call| call.participants.values().find(|p| p.resume_token == resume_token))
+    {
+        Some(participant) => participant.id.clone(),
+        None => return false,
+    };
 
-        for participant_id in call.participants.keys() {
-            if let Some(&channel_id) = state.participant_channels.get(participant_id) {
-                let blob = LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: message_bytes.clone(),
-                };
-                send_ws_push(channel_id, WsMessageType::Text, blob);
-            }
+    println!("Resuming participant {} in call {} on channel {}", participant_id, call_id, channel_id);
+
+    // Drop any stale channel that is still mapped to this participant
+    if let Some(old_channel) = state.participant_channels.remove(&participant_id) {
+        state.connections.remove(&old_channel);
+        if let Some(channels) = state.call_channels.get_mut(call_id) {
+            channels.remove(&old_channel);
         }
     }
-}
-
-fn broadcast_to_call_except(state: &VoiceState, call_id: &str, except_channel: u32, message: WsServerMessage) {
-    if let Some(call) = state.calls.get(call_id) {
-        let message_json = serde_json::to_string(&message).unwrap_or_default();
-        let message_bytes = message_json.into_bytes();
 
-        for participant_id in call.participants.keys() {
-            if let Some(&channel_id) = state.participant_channels.get(participant_id) {
-                if channel_id != except_channel {
-                    let blob = LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: message_bytes.clone(),
-                    };
-                    send_ws_push(channel_id, WsMessageType::Text, blob);
-                }
+    state.connections.insert(channel_id, participant_id.clone());
+    state.participant_channels.insert(participant_id.clone(), channel_id);
+    state.call_channels
+        .entry(call_id.to_string())
+        .or_default()
+        .insert(channel_id);
+
+    // Bring them back into the audio pipeline
+    let processor = match state.calls.get(call_id) {
+        Some(call) => call_audio_processor(&mut state.audio_processors, call),
+        None => return false,
+    };
+    let capabilities = participant_capabilities(state, call_id, &participant_id);
+    let pinned = state.calls.get(call_id)
+        .is_some_and(|call| call.pinned_speakers.contains(&participant_id));
+    {
+        let mut proc = lock_processor(&processor);
+        if !proc.has_participant(&participant_id) {
+            if let Err(e) = add_to_audio_processor(&mut proc, &participant_id, &capabilities) {
+                println!("Failed to add participant to audio processor on resume: {}", e);
             }
+            proc.set_pinned(&participant_id, pinned);
         }
     }
-}
+    // Carry on the output sequence; if it's gone, start a new epoch so the client restarts its buffer
+    let sequence_restarted = !state.participant_output_sequences.contains_key(&participant_id);
+    state.participant_output_sequences.entry(participant_id.clone()).or_insert(0);
+
+    if let Some(call) = state.calls.get_mut(call_id) {
+        if let Some(participant) = call.participants.get_mut(&participant_id) {
+            if participant.reconnecting_since.take().is_some() {
+                // They learn everything from their own JoinSuccess
+                outbox.broadcast_except(call_id, channel_id, WsServerMessage::ParticipantReconnecting {
+                    participant_id: participant_id.clone(),
+                    reconnecting: false,
+                });
+            }
+            if sequence_restarted {
+                participant.output_epoch = participant.output_epoch.wrapping_add(1);
+            }
+        }
 
-fn send_to_channel(channel_id: u32, message: WsServerMessage) {
-    let message_json = serde_json::to_string(&message).unwrap_or_default();
-    let blob = LazyLoadBlob {
-        mime: Some("application/json".to_string()),
-        bytes: message_json.into_bytes(),
-    };
-    send_ws_push(channel_id, WsMessageType::Text, blob);
+        if let Some(message) = call.join_success_since(&participant_id, last_seen_message_id) {
+            outbox.send(channel_id, message);
+        }
+    }
+
+    true
 }
 
-fn send_error_to_channel(channel_id: u32, error: &str) {
-    let message = WsServerMessage::Error(error.to_string());
-    send_to_channel(channel_id, message);
+/// Blank avatar URLs mean "no avatar", so removal always looks the same to clients.
+fn normalize_avatar_url(avatar_url: Option<String>) -> Option<String> {
+    avatar_url
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
 }
 
-fn disconnect_all_call_channels(state: &VoiceState, call_id: &str) {
-    if let Some(channels) = state.call_channels.get(call_id) {
-        println!("Disconnecting {} WebSocket channels for call {}", channels.len(), call_id);
+/// Make chat text safe for every client to render: control characters other than newline and tab
+/// are dropped, as are the bidi overrides that can visually reorder a message, and nothing blank is
+/// accepted. Strings are already valid UTF-8 by the time they get here.
+fn sanitize_chat_content(content: &str) -> Result<String, String> {
+    let sanitized: String = content
+        .chars()
+        .filter(|&c| c == '\n' || c == '\t' || !c.is_control())
+        .filter(|c| !matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'))
+        .collect();
+    let sanitized = sanitized.trim();
+    if sanitized.is_empty() {
+        return Err("Chat message has no displayable text".to_string());
+    }
+    Ok(sanitized.to_string())
+}
 
-        // First send CallEnded message to all participants
+/// Trim branding and reject anything the UI couldn't safely use. Blank fields count as unset.
+fn validate_branding(branding: CallBranding) -> Result<CallBranding, String> {
+    let logo_url = branding.logo_url
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty());
+    if let Some(url) = &logo_url {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err("Logo URL must be http(s)".to_string());
+        }
+        if url.chars().count() > MAX_LOGO_URL_CHARS {
+            return Err(format!("Logo URL is longer than {} characters", MAX_LOGO_URL_CHARS));
+        }
+    }
+
+    let accent_color = branding.accent_color
+        .map(|color| color.trim().to_ascii_lowercase())
+        .filter(|color| !color.is_empty());
+    if let Some(color) = &accent_color {
+        let is_hex_color = color.strip_prefix('#')
+            .is_some_and(|hex| (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit()));
+        if !is_hex_color {
+            return Err("Accent color must be #rgb or #rrggbb".to_string());
+        }
+    }
+
+    Ok(CallBranding { logo_url, accent_color })
+}
+
+/// The participant id and default display name a node joins a call with. Normally that's the
+/// node-scoped id and the node id itself. A call that hides node ids gets an opaque id instead,
+/// the same one whenever the node rejoins while still a participant, and a pleb name.
+fn node_identity(state: &mut VoiceState, call_id: &str, node_id: &str) -> (String, String) {
+    let hides_node_ids = state.calls.get(call_id).is_some_and(|call| call.hide_node_ids);
+    if !hides_node_ids {
+        return (node_participant_id(node_id, call_id), node_id.to_string());
+    }
+    let existing = state.calls.get(call_id).and_then(|call| {
+        call.participants.values()
+            .find(|p| matches!(&p.connection_type, ConnectionType::Node(other) if other == node_id))
+            .map(|p| (p.id.clone(), p.display_name.clone()))
+    });
+    existing.unwrap_or_else(|| (generate_id(), generate_pleb_name_for_call(state, call_id)))
+}
+
+/// A node's participant ID within one call. Scoped so a node can be in several calls at once
+/// without the channel and audio mappings, which are keyed by participant ID, clobbering each other.
+fn node_participant_id(node_id: &str, call_id: &str) -> String {
+    format!("{}@{}", node_id, call_id)
+}
+
+/// Move everyone from `source_id` into `dest_id` and tear the source call down. Participants keep
+/// their ids, roles and resume tokens. Moved clients get `CallMerged` followed by a `JoinSuccess`
+/// for the destination; the destination's existing participants see them join.
+fn merge_call_into(state: &mut VoiceState, source_id: &str, dest_id: &str) -> Result<(), String> {
+    if source_id == dest_id {
+        return Err("Cannot merge a call into itself".to_string());
+    }
+    if !state.calls.contains_key(dest_id) {
+        return Err(call_missing_error(state, dest_id));
+    }
+    let mut source = match state.calls.remove(source_id) {
+        Some(call) => call,
+        None => return Err(call_missing_error(state, source_id)),
+    };
+    println!("Merging call {} into {}", source_id, dest_id);
+
+    let source_channels = state.call_channels.remove(source_id).unwrap_or_default();
+    state.audio_processors.remove(source_id);
+    let source_names = state.used_pleb_names.remove(source_id).unwrap_or_default();
+
+    let Some(dest) = state.calls.get_mut(dest_id) else {
+        return Err("Call not found".to_string());
+    };
+    let existing: Vec<String> = dest.participants.keys().cloned().collect();
+
+    // Keep the source's join order after everyone already in the destination
+    let mut incoming: Vec<Participant> = source.participants.drain().map(|(_, p)| p).collect();
+    incoming.sort_by_key(|p| p.seat);
+
+    let mut moved = Vec::new();
+    for mut participant in incoming {
+        // A node already in both calls keeps their destination seat
+        let already_present = match &participant.connection_type {
+            ConnectionType::Node(node_id) => dest.participants.values().any(|p| {
+                matches!(&p.connection_type, ConnectionType::Node(other) if other == node_id)
+            }),
+            ConnectionType::Browser => false,
+        };
+        if already_present {
+            continue;
+        }
+        participant.seat = dest.next_seat;
+        dest.next_seat += 1;
+        if let Role::Custom(name) = &participant.role {
+            if let Some(&role_permissions) = source.custom_roles.get(name) {
+                dest.custom_roles.entry(name.clone()).or_insert(role_permissions);
+            }
+        }
+        if source.pinned_speakers.contains(&participant.id) {
+            dest.pinned_speakers.insert(participant.id.clone());
+        }
+        moved.push(participant.id.clone());
+        dest.participants.insert(participant.id.clone(), participant);
+    }
+
+    dest.chat_history.append(&mut source.chat_history);
+    for (participant_id, bytes) in source.bytes_sent.drain() {
+        *dest.bytes_sent.entry(participant_id).or_default() += bytes;
+    }
+    dest.playout_stats.extend(source.playout_stats.drain());
+    for (node_id, expires_at) in source.reserved_slots.drain() {
+        let reservation = dest.reserved_slots.entry(node_id).or_default();
+        *reservation = (*reservation).max(expires_at);
+    }
+    dest.chat_history.sort_by_key(|message| message.timestamp);
+    dest.trim_chat_history();
+
+    // Rebind moved channels; any others belonged to duplicates and are closed. Their connection
+    // mapping goes first so the resulting close event can't disturb their destination connection.
+    for channel_id in source_channels {
+        let is_moved = state.connections.get(&channel_id).is_some_and(|pid| moved.contains(pid));
+        if is_moved {
+            state.call_channels.entry(dest_id.to_string()).or_default().insert(channel_id);
+        } else {
+            if let Some(participant_id) = state.connections.remove(&channel_id) {
+                state.participant_channels.remove(&participant_id);
+                state.participant_output_sequences.remove(&participant_id);
+                state.audio_violations.remove(&participant_id);
+                state.audio_batches.remove(&participant_id);
+            }
+            close_channel(state, channel_id, WS_CLOSE_NORMAL, "Call merged");
+        }
+    }
+
+    for token in state.node_auth_tokens.values_mut() {
+        if token.call_id == source_id {
+            token.call_id = dest_id.to_string();
+        }
+    }
+    state.used_pleb_names.entry(dest_id.to_string()).or_default().extend(source_names);
+
+    // Register the newcomers with the destination's mixer
+    if let Some(call) = state.calls.get(dest_id) {
+        let processor = call_audio_processor(&mut state.audio_processors, call);
+        let mut proc = lock_processor(&processor);
+        for participant_id in &moved {
+            let Some(participant) = call.participants.get(participant_id) else { continue };
+            if participant.reconnecting_since.is_some() {
+                continue; // Re-registered when they resume
+            }
+            if let Err(e) = add_to_audio_processor(&mut proc, participant_id, &participant.capabilities) {
+                println!("Failed to add participant to audio processor on merge: {}", e);
+            }
+            proc.set_pinned(participant_id, call.pinned_speakers.contains(participant_id));
+        }
+    }
+
+    unserve_call_ui(&call_path(source_id));
+    remove_call_alias(state, source_id);
+    record_call_ended(state, source_id, CallEndReason::MergedInto(dest_id.to_string()));
+
+    // Tell everyone what happened
+    let Some(call) = state.calls.get(dest_id) else { return Ok(()) };
+    for participant_id in &moved {
+        if let Some(&channel_id) = state.participant_channels.get(participant_id) {
+            send_to_channel(state, channel_id, WsServerMessage::CallMerged { call_id: dest_id.to_string() });
+            if let Some(message) = call.join_success(participant_id) {
+                send_to_channel(state, channel_id, message);
+            }
+        }
+    }
+    for participant_id in &existing {
+        let Some(&channel_id) = state.participant_channels.get(participant_id) else { continue };
+        for moved_id in &moved {
+            if let Some(participant) = call.participants.get(moved_id) {
+                send_to_channel(state, channel_id, WsServerMessage::ParticipantJoined(
+                    WsParticipantJoined { participant: participant.to_info(!call.hide_node_ids) }
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Forget a channel whose participant is no longer in any call and close it. Messages go out
+/// directly since the channel is gone by the time the handler's outbox flushes.
+fn drop_stale_connection(state: &mut VoiceState, channel_id: u32, participant_id: &str) {
+    println!("Channel {} maps to participant {} who is in no call, cleaning up", channel_id, participant_id);
+    state.connections.remove(&channel_id);
+    if state.participant_channels.get(participant_id) == Some(&channel_id) {
+        state.participant_channels.remove(participant_id);
+    }
+    for channels in state.call_channels.values_mut() {
+        channels.remove(&channel_id);
+    }
+    state.participant_output_sequences.remove(participant_id);
+    state.audio_violations.remove(participant_id);
+    state.audio_batches.remove(participant_id);
+
+    send_to_channel(state, channel_id, WsServerMessage::Error("Not in a call".to_string()));
+    send_to_channel(state, channel_id, WsServerMessage::CloseConnection);
+    close_channel(state, channel_id, WS_CLOSE_NORMAL, "Not in a call");
+}
+
+/// Fully remove a participant from a call, ending the call if they were the host or the last one left.
+fn remove_participant_from_call(state: &mut VoiceState, call_id: &str, participant_id: &str) {
+    let host_id = match state.calls.get(call_id) {
+        Some(call) => call.host_id.clone(),
+        None => return,
+    };
+
+    // Clean up any connection mapping that is still around
+    if let Some(channel_id) = state.participant_channels.remove(participant_id) {
+        state.connections.remove(&channel_id);
+        if let Some(channels) = state.call_channels.get_mut(call_id) {
+            channels.remove(&channel_id);
+        }
+    }
+
+    // Remove participant from audio processor
+    if let Some(processor) = state.audio_processors.get(call_id) {
+        lock_processor(processor).remove_participant(participant_id);
+    }
+
+    // Clean up output sequence numbers for this participant
+    state.participant_output_sequences.remove(participant_id);
+    state.audio_violations.remove(participant_id);
+    state.audio_batches.remove(participant_id);
+    let mut outbox = Outbox::default();
+    release_floor(state, &mut outbox, call_id, participant_id);
+    stop_screen_share(state, &mut outbox, call_id, participant_id);
+    outbox.flush(state);
+
+    // Check if this participant is the host
+    let is_host_leaving = host_id.as_deref() == Some(participant_id);
+
+    // Determine if we should end the call
+    let should_end_call = {
+        if let Some(call) = state.calls.get_mut(call_id) {
+            call.participants.remove(participant_id);
+            call.pinned_speakers.remove(participant_id);
+            let is_empty = call.participants.is_empty();
+            !call.persistent && (is_empty || is_host_leaving)
+        } else {
+            false
+        }
+    };
+
+    emit_analytics(state, call_id, AnalyticsEventKind::ParticipantLeft {
+        participant_id: participant_id.to_string(),
+    });
+    if should_end_call {
+        println!("Ending call {} - host leaving: {}", call_id, is_host_leaving);
+        let reason = if is_host_leaving { CallEndReason::HostLeft } else { CallEndReason::Empty };
+        end_call(state, call_id, reason);
+    } else {
+        // Just notify remaining participants
+        let notification = WsServerMessage::ParticipantLeft { participant_id: participant_id.to_string() };
+        broadcast_to_call(state, call_id, notification);
+        ensure_valid_host(state, call_id);
+    }
+}
+
+/// Invariant: `host_id` names a current participant, or is None only once the call is empty. Run after
+/// any participant removal. A dangling host migrates to the longest-seated admin (or, failing that,
+/// the longest-seated participant) and everyone gets a FullState carrying the new host.
+fn ensure_valid_host(state: &mut VoiceState, call_id: &str) {
+    let Some(call) = state.calls.get_mut(call_id) else {
+        return;
+    };
+    let host_valid = match &call.host_id {
+        Some(host_id) => call.participants.contains_key(host_id),
+        None => call.participants.is_empty(),
+    };
+    if host_valid {
+        return;
+    }
+
+    let new_host = call.participants.values()
+        .min_by_key(|p| (p.role != Role::Admin, p.seat))
+        .map(|p| p.id.clone());
+    println!("Call {} host {:?} is gone, migrating to {:?}", call_id, call.host_id, new_host);
+    call.host_id = new_host;
+    debug_assert!(call.host_id.iter().all(|id| call.participants.contains_key(id)));
+
+    if call.host_id.is_some() {
+        let full_state = call.full_state();
+        broadcast_to_call(state, call_id, full_state);
+    }
+}
+
+/// The one way a call is torn down, in this order: tell every channel still on it why and close
+/// them (`disconnect_all_call_channels`), stop serving its UI, then purge the call and every mapping
+/// that refers to its participants. Nothing is purged until each channel has been sent CallEnded.
+fn end_call(state: &mut VoiceState, call_id: &str, reason: CallEndReason) {
+    // Notify and disconnect, while every channel is still mapped to the call
+    disconnect_all_call_channels(state, call_id, &reason);
+
+    // Unserve the UI
+    unserve_call_ui(&call_path(call_id));
+    remove_call_alias(state, call_id);
+
+    // Purge. The close events still to come find nothing mapped and are no-ops.
+    let participant_ids: Vec<String> = state.calls.remove(call_id)
+        .map(|call| call.participants.into_keys().collect())
+        .unwrap_or_default();
+    state.connections.retain(|_, participant_id| !participant_ids.contains(participant_id));
+    for participant_id in &participant_ids {
+        state.participant_channels.remove(participant_id);
+        state.participant_output_sequences.remove(participant_id);
+        state.audio_violations.remove(participant_id);
+        state.audio_batches.remove(participant_id);
+    }
+    let connections = &state.connections;
+    state.bot_channels.retain(|channel_id, _| connections.contains_key(channel_id));
+    state.channel_last_seen.retain(|channel_id, _| connections.contains_key(channel_id));
+    state.channel_pings.retain(|channel_id, _| connections.contains_key(channel_id));
+    state.node_auth_tokens.retain(|_, t| t.call_id != call_id);
+    record_call_ended(state, call_id, reason);
+    state.used_pleb_names.remove(call_id);
+    state.call_channels.remove(call_id);
+    state.audio_processors.remove(call_id);
+}
+
+/// End every call on the node, returning how many there were.
+fn end_all_calls(state: &mut VoiceState, reason: CallEndReason) -> usize {
+    let call_ids: Vec<String> = state.calls.keys().cloned().collect();
+    println!("Ending all {} calls: {}", call_ids.len(), reason.describe());
+    for call_id in &call_ids {
+        end_call(state, call_id, reason.clone());
+    }
+    call_ids.len()
+}
+
+/// Stop serving the in-call UI at `path`. Without an HTTP server (as in tests) nothing was served.
+fn unserve_call_ui(path: &str) {
+    let Some(server) = hyperware_app_common::get_server() else { return };
+    if let Err(e) = server.unserve_ui("ui-call", vec![path]) {
+        println!("Failed to unserve UI at {}: {:?}", path, e);
+    }
+}
+
+/// Periodic cleanup, piggybacked on incoming WebSocket and HTTP traffic (we have no timer of our own)
+/// and throttled to `HOUSEKEEPING_INTERVAL_MS`.
+fn run_housekeeping(state: &mut VoiceState) {
+    let now = current_timestamp().unwrap_or(0);
+    if now.saturating_sub(state.last_housekeeping) < HOUSEKEEPING_INTERVAL_MS {
+        return;
+    }
+    state.last_housekeeping = now;
+
+    repair_connection_state(state);
+    expire_silent_channels(state, now);
+    ping_channels(state, now);
+    expire_reconnecting_participants(state, now);
+    expire_unjoined_calls(state, now / 1000);
+    enforce_max_durations(state, now / 1000);
+    demote_idle_speakers(state);
+    advise_silent_calls(state, now / 1000);
+    flush_stale_audio_batches(state, now);
+    state.ended_calls.retain(|_, tombstone| now.saturating_sub(tombstone.ended_at) < CALL_TOMBSTONE_TTL_MS);
+    let connections = &state.connections;
+    state.bot_channels.retain(|channel_id, _| connections.contains_key(channel_id));
+    state.channel_last_seen.retain(|channel_id, _| connections.contains_key(channel_id));
+    state.channel_pings.retain(|channel_id, _| connections.contains_key(channel_id));
+    state.recent_call_creations.retain(|_, recent| {
+        recent.last().is_some_and(|&created_at| now.saturating_sub(created_at) < CREATE_CALL_WINDOW_MS)
+    });
+    for call in state.calls.values_mut() {
+        call.reserved_slots.retain(|_, &mut expires_at| expires_at > now);
+    }
+    // Strikes outlive the window only while a backoff could still grow from them
+    state.join_attempts.retain(|_, attempts| {
+        now < attempts.blocked_until.saturating_add(JOIN_WINDOW_MS)
+            || attempts.recent.last().is_some_and(|&at| now.saturating_sub(at) < JOIN_WINDOW_MS)
+    });
+}
+
+/// Lower any raised hands among `participant_ids`, announcing them together in one HandsLowered.
+/// Hands already down are left out, so promotion clearing a hand and LowerAllHands can't overlap.
+fn lower_hands(state: &mut VoiceState, outbox: &mut Outbox, call_id: &str, participant_ids: &[String]) {
+    let Some(call) = state.calls.get_mut(call_id) else { return };
+    let mut lowered = Vec::new();
+    for participant_id in participant_ids {
+        if let Some(participant) = call.participants.get_mut(participant_id) {
+            if participant.hand_raised {
+                participant.hand_raised = false;
+                lowered.push(participant_id.clone());
+            }
+        }
+    }
+    if !lowered.is_empty() {
+        outbox.broadcast(call_id, WsServerMessage::HandsLowered { participant_ids: lowered });
+    }
+}
+
+/// Tell a participant themselves that they can now speak, or no longer can, so their client can
+/// switch its mic pipeline straight away instead of working it out from their new role.
+fn notify_speaking_enabled(state: &VoiceState, outbox: &mut Outbox, participant_id: &str, enabled: bool) {
+    if let Some(&channel_id) = state.participant_channels.get(participant_id) {
+        outbox.send(channel_id, WsServerMessage::SpeakingEnabled { enabled });
+    }
+}
+
+/// Give a newly promoted speaker a fresh idle window so they aren't demoted straight away.
+fn mark_speaker_active(state: &VoiceState, call_id: &str, participant_id: &str) {
+    if let Some(processor) = state.audio_processors.get(call_id) {
+        lock_processor(processor).mark_active(participant_id);
+    }
+}
+
+/// Demote Speakers who have been silent longer than their call's idle timeout to Listener, freeing
+/// their decoder. Pinned speakers are exempt.
+fn demote_idle_speakers(state: &mut VoiceState) {
+    let mut demotions = Vec::new();
+    for call in state.calls.values() {
+        let Some(timeout_secs) = call.idle_speaker_timeout_secs else { continue };
+        let Some(processor) = state.audio_processors.get(&call.id) else { continue };
+
+        let idle = lock_processor(processor).idle_participants(std::time::Duration::from_secs(timeout_secs));
+        for participant_id in idle {
+            let is_idle_speaker = call.participants.get(&participant_id)
+                .is_some_and(|p| p.role == Role::Speaker && p.reconnecting_since.is_none());
+            if is_idle_speaker && !call.pinned_speakers.contains(&participant_id) {
+                demotions.push((call.id.clone(), participant_id));
+            }
+        }
+    }
+
+    let mut outbox = Outbox::default();
+    for (call_id, participant_id) in demotions {
+        println!("Demoting idle speaker {} in call {}", participant_id, call_id);
+        if let Some(participant) = state.calls.get_mut(&call_id)
+            .and_then(|call| call.participants.get_mut(&participant_id))
+        {
+            participant.role = Role::Listener;
+        }
+        if let Some(processor) = state.audio_processors.get(&call_id) {
+            lock_processor(processor).release_decoder(&participant_id);
+        }
+        state.audio_violations.remove(&participant_id);
+
+        outbox.broadcast(&call_id, WsServerMessage::RoleUpdated(WsRoleUpdate {
+            participant_id: participant_id.clone(),
+            new_role: Role::Listener,
+        }));
+        notify_speaking_enabled(state, &mut outbox, &participant_id, false);
+        revoke_lost_privileges(state, &mut outbox, &call_id, &participant_id);
+        emit_analytics(state, &call_id, AnalyticsEventKind::RoleChanged {
+            participant_id,
+            from: Role::Speaker,
+            to: Role::Listener,
+        });
+    }
+    outbox.flush(state);
+}
+
+/// Send a SilenceAdvisory to calls where every connected Speaker and Admin has been silent for
+/// SILENCE_ADVISORY_AFTER_SECS, usually because they're all muted without realising it. Push-to-talk
+/// calls are quiet between turns by design, so they're left alone.
+fn advise_silent_calls(state: &mut VoiceState, now_secs: u64) {
+    let mut advisories = Vec::new();
+    for call in state.calls.values() {
+        if call.push_to_talk {
+            continue;
+        }
+        let recently_advised = call.last_silence_advisory
+            .is_some_and(|advised_at| now_secs.saturating_sub(advised_at) < SILENCE_ADVISORY_COOLDOWN_SECS);
+        if recently_advised {
+            continue;
+        }
+        let Some(processor) = state.audio_processors.get(&call.id) else { continue };
+
+        let speakers: Vec<&Participant> = call.participants.values()
+            .filter(|p| matches!(p.role, Role::Speaker | Role::Admin) && p.reconnecting_since.is_none())
+            .collect();
+        if speakers.is_empty() {
+            continue;
+        }
+        let idle = lock_processor(processor).idle_participants(std::time::Duration::from_secs(SILENCE_ADVISORY_AFTER_SECS));
+        if speakers.iter().all(|p| idle.contains(&p.id)) {
+            advisories.push((call.id.clone(), speakers.iter().all(|p| p.is_muted)));
+        }
+    }
+
+    for (call_id, all_muted) in advisories {
+        println!("Call {} has been silent for {}s, advising (all muted: {})", call_id, SILENCE_ADVISORY_AFTER_SECS, all_muted);
+        if let Some(call) = state.calls.get_mut(&call_id) {
+            call.last_silence_advisory = Some(now_secs);
+        }
+        broadcast_to_call(state, &call_id, WsServerMessage::SilenceAdvisory { all_muted });
+    }
+}
+
+/// Refuse to create more calls when the node is at capacity or the caller has created too many
+/// recently. Browser requests all arrive via our own http-server, so they share one budget.
+fn check_create_call_limits(state: &mut VoiceState, source_node: &str) -> Result<(), String> {
+    if state.calls.len() >= MAX_ACTIVE_CALLS {
+        return Err("Too many active calls on this node, try again later".to_string());
+    }
+
+    let now = current_timestamp().unwrap_or(0);
+    let recent = state.recent_call_creations.entry(source_node.to_string()).or_default();
+    recent.retain(|&created_at| now.saturating_sub(created_at) < CREATE_CALL_WINDOW_MS);
+    if recent.len() >= MAX_CALLS_CREATED_PER_WINDOW {
+        println!("Rate limiting create_call from {}", source_node);
+        return Err("Creating calls too quickly, try again later".to_string());
+    }
+    recent.push(now);
+    Ok(())
+}
+
+/// Throttle a joiner who keeps (re)joining, so one client stuck in a reconnect loop can't flood the
+/// room with join/leave broadcasts. Past MAX_JOINS_PER_WINDOW they're refused for a backoff that
+/// doubles each time they hit the limit again.
+fn check_join_rate(state: &mut VoiceState, joiner: &str) -> Result<(), String> {
+    let now = current_timestamp().unwrap_or(0);
+    let attempts = state.join_attempts.entry(joiner.to_string()).or_default();
+    if now < attempts.blocked_until {
+        let wait_secs = (attempts.blocked_until - now).div_ceil(1000);
+        return Err(format!("Reconnecting too quickly, slow down and retry in {}s", wait_secs));
+    }
+
+    attempts.recent.retain(|&at| now.saturating_sub(at) < JOIN_WINDOW_MS);
+    if attempts.recent.is_empty() {
+        attempts.strikes = 0;
+    }
+    if attempts.recent.len() >= MAX_JOINS_PER_WINDOW {
+        attempts.strikes += 1;
+        let backoff = JOIN_BACKOFF_BASE_MS
+            .saturating_mul(1 << (attempts.strikes - 1).min(16))
+            .min(JOIN_BACKOFF_MAX_MS);
+        attempts.blocked_until = now + backoff;
+        attempts.recent.clear();
+        println!("Throttling joins from {} for {}ms", joiner, backoff);
+        return Err(format!("Reconnecting too quickly, slow down and retry in {}s", backoff.div_ceil(1000)));
+    }
+    attempts.recent.push(now);
+    Ok(())
+}
+
+/// Reconcile `connections`, `participant_channels` and `call_channels` with each other and with the
+/// calls' participants. `connections` is trusted for which participant a channel belongs to: a
+/// channel whose participant is gone, or who has since moved to another channel, is closed. A
+/// participant left with no channel and no reconnect pending is disconnected as if their channel
+/// had closed.
+fn repair_connection_state(state: &mut VoiceState) -> ConsistencyReport {
+    let mut report = ConsistencyReport::default();
+    let participant_calls: HashMap<String, String> = state.calls.values()
+        .flat_map(|call| call.participants.keys().map(move |participant_id| (participant_id.clone(), call.id.clone())))
+        .collect();
+
+    // Restore reverse mappings that went missing, then close channels that lost their participant
+    for (&channel_id, participant_id) in &state.connections {
+        if participant_calls.contains_key(participant_id) && !state.participant_channels.contains_key(participant_id) {
+            state.participant_channels.insert(participant_id.clone(), channel_id);
+            report.stale_mappings += 1;
+        }
+    }
+    let orphaned: Vec<u32> = state.connections.iter()
+        .filter(|(channel_id, participant_id)| {
+            !participant_calls.contains_key(*participant_id)
+                || state.participant_channels.get(*participant_id) != Some(*channel_id)
+        })
+        .map(|(&channel_id, _)| channel_id)
+        .collect();
+    for channel_id in orphaned {
+        state.connections.remove(&channel_id);
+        state.channel_last_seen.remove(&channel_id);
+        state.channel_pings.remove(&channel_id);
+        close_channel(state, channel_id, WS_CLOSE_NORMAL, "Not in a call");
+        report.orphaned_channels.push(channel_id);
+    }
+
+    let mappings = state.participant_channels.len();
+    state.participant_channels.retain(|participant_id, channel_id| {
+        state.connections.get(channel_id) == Some(participant_id)
+    });
+    report.stale_mappings += mappings - state.participant_channels.len();
+
+    // Each call's channel set holds exactly its participants' channels
+    for (call_id, channels) in state.call_channels.iter_mut() {
+        let before = channels.len();
+        channels.retain(|channel_id| {
+            state.connections.get(channel_id).and_then(|participant_id| participant_calls.get(participant_id)) == Some(call_id)
+        });
+        report.stale_mappings += before - channels.len();
+    }
+    for (&channel_id, participant_id) in &state.connections {
+        if let Some(call_id) = participant_calls.get(participant_id) {
+            if state.call_channels.entry(call_id.clone()).or_default().insert(channel_id) {
+                report.stale_mappings += 1;
+            }
+        }
+    }
+
+    let channelless: Vec<(String, String, u64)> = state.calls.values()
+        .flat_map(|call| {
+            call.participants.values()
+                .filter(|p| p.reconnecting_since.is_none() && !state.participant_channels.contains_key(&p.id))
+                .map(move |p| (call.id.clone(), p.id.clone(), call.reconnect_window_secs))
+        })
+        .collect();
+    for (call_id, participant_id, reconnect_window_secs) in channelless {
+        if reconnect_window_secs > 0 {
+            mark_reconnecting(state, &call_id, &participant_id);
+        } else {
+            remove_participant_from_call(state, &call_id, &participant_id);
+        }
+        report.channelless_participants.push(participant_id);
+    }
+
+    if !report.orphaned_channels.is_empty() || report.stale_mappings > 0 || !report.channelless_participants.is_empty() {
+        println!("Repaired connection state: {:?}", report);
+    }
+    report
+}
+
+/// Drop WebSocket channels that have gone quiet for SILENT_CHANNEL_TIMEOUT_MS, as if they had closed,
+/// so mixes stop going to a connection that died without a Close frame. Bots have no heartbeat and
+/// leave explicitly, so their channels are exempt.
+fn expire_silent_channels(state: &mut VoiceState, now: u64) {
+    let silent: Vec<u32> = state.connections.keys()
+        .filter(|channel_id| !state.bot_channels.contains_key(channel_id))
+        .filter(|channel_id| {
+            state.channel_last_seen.get(channel_id)
+                .is_some_and(|&seen| now.saturating_sub(seen) >= SILENT_CHANNEL_TIMEOUT_MS)
+        })
+        .copied()
+        .collect();
+
+    for channel_id in silent {
+        println!("Channel {} has been silent too long, dropping it", channel_id);
+        close_channel(state, channel_id, WS_CLOSE_GOING_AWAY, "Connection timed out");
+        handle_disconnect(state, channel_id);
+    }
+}
+
+/// Ping joined WebSocket clients every keepalive interval, dropping any that have left
+/// `miss_threshold` pings in a row unanswered as if they had closed. Like the silence timeout this
+/// rides on housekeeping, so pings go out only while some traffic reaches the process. Bots are exempt.
+fn ping_channels(state: &mut VoiceState, now: u64) {
+    let config = state.keepalive.unwrap_or_default();
+    let due: Vec<u32> = state.connections.keys()
+        .filter(|channel_id| !state.bot_channels.contains_key(channel_id))
+        .filter(|channel_id| {
+            let next_ping_at = state.channel_pings.get(channel_id)
+                .map_or(0, |pings| pings.last_sent.saturating_add(config.interval_ms));
+            now >= next_ping_at
+        })
+        .copied()
+        .collect();
+
+    for channel_id in due {
+        let pings = state.channel_pings.entry(channel_id).or_default();
+        if pings.unanswered >= config.miss_threshold {
+            println!("Channel {} missed {} pings, dropping it", channel_id, pings.unanswered);
+            close_channel(state, channel_id, WS_CLOSE_GOING_AWAY, "Connection timed out");
+            handle_disconnect(state, channel_id);
+            continue;
+        }
+        pings.unanswered += 1;
+        pings.last_sent = now;
+        send_to_channel(state, channel_id, WsServerMessage::Ping);
+    }
+}
+
+/// Clean up calls that were created but never joined, e.g. links generated and never used.
+fn expire_unjoined_calls(state: &mut VoiceState, now_secs: u64) {
+    let expired: Vec<String> = state.calls.values()
+        .filter(|call| call.participants.is_empty() && call.creator_id.is_none())
+        .filter(|call| now_secs.saturating_sub(call.created_at) >= UNJOINED_CALL_TTL_SECS)
+        .map(|call| call.id.clone())
+        .collect();
+
+    for call_id in expired {
+        println!("Call {} was never joined, cleaning it up", call_id);
+        end_call(state, &call_id, CallEndReason::NeverJoined);
+    }
+}
+
+/// Warn calls that are approaching their maximum duration and end the ones that have reached it.
+fn enforce_max_durations(state: &mut VoiceState, now_secs: u64) {
+    let mut expired = Vec::new();
+    let mut warnings = Vec::new();
+    for call in state.calls.values_mut() {
+        let Some(max_duration_secs) = call.max_duration_secs else { continue };
+        let ends_at = call.created_at.saturating_add(max_duration_secs);
+        if now_secs >= ends_at {
+            expired.push(call.id.clone());
+            continue;
+        }
+
+        // Only the most urgent warning due goes out, so a short call doesn't get several at once
+        let seconds_remaining = ends_at - now_secs;
+        let due = CALL_ENDING_SOON_WARNINGS_SECS.iter()
+            .filter(|&&warning_secs| seconds_remaining <= warning_secs)
+            .count();
+        if due > call.ending_soon_warnings_sent {
+            call.ending_soon_warnings_sent = due;
+            warnings.push((call.id.clone(), seconds_remaining));
+        }
+    }
+
+    for (call_id, seconds_remaining) in warnings {
+        broadcast_to_call(state, &call_id, WsServerMessage::CallEndingSoon { seconds_remaining });
+    }
+    for call_id in expired {
+        println!("Call {} reached its maximum duration, ending it", call_id);
+        end_call(state, &call_id, CallEndReason::MaxDurationReached);
+    }
+}
+
+fn flush_audio_batch(state: &mut VoiceState, participant_id: &str) {
+    if let Some(batch) = state.audio_batches.remove(participant_id) {
+        if let Some(&channel_id) = state.participant_channels.get(participant_id) {
+            let repacketize = state.calls.values()
+                .find_map(|call| call.participants.get(participant_id))
+                .is_some_and(|p| p.repacketize_batches);
+            let mut frames = if repacketize { repacketize_frames(batch.frames) } else { batch.frames };
+
+            if frames.len() == 1 {
+                send_to_channel(state, channel_id, WsServerMessage::AudioData(frames.remove(0)));
+            } else {
+                send_to_channel(state, channel_id, WsServerMessage::AudioDataBatch { frames });
+            }
+        }
+    }
+}
+
+/// Combine runs of up to `MAX_REPACKETIZED_FRAMES` batched frames into single multi-frame Opus
+/// packets. Each combined frame keeps the sequence and timestamp of its first frame. A run that
+/// can't be combined (e.g. the encoder changed mode mid-run) is passed through unchanged.
+fn repacketize_frames(frames: Vec<WsAudioData>) -> Vec<WsAudioData> {
+    frames.chunks(MAX_REPACKETIZED_FRAMES)
+        .flat_map(|run| {
+            let packets: Vec<Vec<u8>> = run.iter().map(|frame| base64_to_bytes(&frame.data)).collect();
+            match audio::repacketize(&packets) {
+                Ok(combined) => {
+                    let mut frame = run[0].clone();
+                    frame.data = bytes_to_base64(&combined);
+                    vec![frame]
+                }
+                Err(e) => {
+                    println!("Sending {} frames uncombined: {}", run.len(), e);
+                    run.to_vec()
+                }
+            }
+        })
+        .collect()
+}
+
+/// Send partial batches whose oldest frame has waited long enough, keeping batching latency bounded.
+fn flush_stale_audio_batches(state: &mut VoiceState, now: u64) {
+    let stale: Vec<String> = state.audio_batches.iter()
+        .filter(|(_, batch)| now.saturating_sub(batch.started_at) >= AUDIO_BATCH_MAX_DELAY_MS)
+        .map(|(participant_id, _)| participant_id.clone())
+        .collect();
+
+    for participant_id in stale {
+        flush_audio_batch(state, &participant_id);
+    }
+}
+
+fn record_call_ended(state: &mut VoiceState, call_id: &str, reason: CallEndReason) {
+    emit_analytics(state, call_id, AnalyticsEventKind::CallEnded { reason: reason.clone() });
+    state.ended_calls.insert(call_id.to_string(), CallTombstone {
+        ended_at: current_timestamp().unwrap_or(0),
+        reason,
+    });
+}
+
+/// Error for a call id that isn't live, distinguishing a recently ended call from an unknown one.
+fn call_missing_error(state: &VoiceState, call_id: &str) -> String {
+    match state.ended_calls.get(call_id) {
+        Some(tombstone) => format!("This call has ended - {}", tombstone.reason.describe()),
+        None => "Call not found".to_string(),
+    }
+}
+
+fn expire_reconnecting_participants(state: &mut VoiceState, now: u64) {
+    let expired: Vec<(String, String)> = state.calls.values()
+        .flat_map(|call| {
+            let window_ms = call.reconnect_window_secs * 1000;
+            call.participants.values()
+                .filter(move |p| p.reconnecting_since.is_some_and(|since| now.saturating_sub(since) >= window_ms))
+                .map(move |p| (call.id.clone(), p.id.clone()))
+        })
+        .collect();
+
+    for (call_id, participant_id) in expired {
+        println!("Participant {} did not reconnect to call {} in time", participant_id, call_id);
+        remove_participant_from_call(state, &call_id, &participant_id);
+    }
+}
+
+fn find_participant_call(state: &VoiceState, participant_id: &str) -> Option<(String, Role)> {
+    for (call_id, call) in &state.calls {
+        if let Some(participant) = call.participants.get(participant_id) {
+            return Some((call_id.clone(), participant.role.clone()));
+        }
+    }
+    None
+}
+
+fn broadcast_to_call(state: &VoiceState, call_id: &str, message: WsServerMessage) {
+    if let Some(call) = state.calls.get(call_id) {
+        let message_json = serde_json::to_string(&message).unwrap_or_default();
+        let message_bytes = message_json.into_bytes();
+
+        for participant_id in call.participants.keys() {
+            if let Some(&channel_id) = state.participant_channels.get(participant_id) {
+                let blob = LazyLoadBlob {
+                    mime: Some("application/json".to_string()),
+                    bytes: message_bytes.clone(),
+                };
+                push_to_channel(state, channel_id, WsMessageType::Text, blob);
+            }
+        }
+    }
+}
+
+fn broadcast_to_call_except(state: &VoiceState, call_id: &str, except_channel: u32, message: WsServerMessage) {
+    if let Some(call) = state.calls.get(call_id) {
+        let message_json = serde_json::to_string(&message).unwrap_or_default();
+        let message_bytes = message_json.into_bytes();
+
+        for participant_id in call.participants.keys() {
+            if let Some(&channel_id) = state.participant_channels.get(participant_id) {
+                if channel_id != except_channel {
+                    let blob = LazyLoadBlob {
+                        mime: Some("application/json".to_string()),
+                        bytes: message_bytes.clone(),
+                    };
+                    push_to_channel(state, channel_id, WsMessageType::Text, blob);
+                }
+            }
+        }
+    }
+}
+
+/// Picks recipients by role. It also gets the role's resolved permissions, since custom roles only
+/// mean something through them.
+type RolePredicate = fn(&Role, Permissions) -> bool;
+
+/// Send only to the call's participants whose role matches, e.g. moderator-only notices.
+fn broadcast_to_role(state: &VoiceState, call_id: &str, role_predicate: RolePredicate, message: WsServerMessage) {
+    if let Some(call) = state.calls.get(call_id) {
+        let message_json = serde_json::to_string(&message).unwrap_or_default();
+        let message_bytes = message_json.into_bytes();
+
+        for participant in call.participants.values() {
+            if !role_predicate(&participant.role, call.permissions(&participant.role)) {
+                continue;
+            }
+            if let Some(&channel_id) = state.participant_channels.get(&participant.id) {
+                let blob = LazyLoadBlob {
+                    mime: Some("application/json".to_string()),
+                    bytes: message_bytes.clone(),
+                };
+                push_to_channel(state, channel_id, WsMessageType::Text, blob);
+            }
+        }
+    }
+}
+
+/// Messages produced while handling one client message. Handlers queue into it freely, even while
+/// holding borrows into the state, and everything is sent in order once handling is done.
+#[derive(Default)]
+struct Outbox {
+    messages: Vec<(Recipient, WsServerMessage)>,
+}
+
+enum Recipient {
+    Channel(u32),
+    Call(String),
+    CallExcept(String, u32),
+    Role(String, RolePredicate),
+}
+
+impl Outbox {
+    fn send(&mut self, channel_id: u32, message: WsServerMessage) {
+        self.messages.push((Recipient::Channel(channel_id), message));
+    }
+
+    fn error(&mut self, channel_id: u32, error: &str) {
+        self.send(channel_id, WsServerMessage::Error(error.to_string()));
+    }
+
+    fn broadcast(&mut self, call_id: &str, message: WsServerMessage) {
+        self.messages.push((Recipient::Call(call_id.to_string()), message));
+    }
+
+    fn broadcast_except(&mut self, call_id: &str, except_channel: u32, message: WsServerMessage) {
+        self.messages.push((Recipient::CallExcept(call_id.to_string(), except_channel), message));
+    }
+
+    fn broadcast_to_role(&mut self, call_id: &str, role_predicate: RolePredicate, message: WsServerMessage) {
+        self.messages.push((Recipient::Role(call_id.to_string(), role_predicate), message));
+    }
+
+    /// Send everything queued. Call recipients are resolved now, against the final state.
+    fn flush(self, state: &VoiceState) {
+        for (recipient, message) in self.messages {
+            match recipient {
+                Recipient::Channel(channel_id) => send_to_channel(state, channel_id, message),
+                Recipient::Call(call_id) => broadcast_to_call(state, &call_id, message),
+                Recipient::CallExcept(call_id, except_channel) => {
+                    broadcast_to_call_except(state, &call_id, except_channel, message)
+                }
+                Recipient::Role(call_id, role_predicate) => {
+                    broadcast_to_role(state, &call_id, role_predicate, message)
+                }
+            }
+        }
+    }
+}
+
+fn send_to_channel(state: &VoiceState, channel_id: u32, message: WsServerMessage) {
+    let message_json = serde_json::to_string(&message).unwrap_or_default();
+    let blob = LazyLoadBlob {
+        mime: Some("application/json".to_string()),
+        bytes: message_json.into_bytes(),
+    };
+    push_to_channel(state, channel_id, WsMessageType::Text, blob);
+}
+
+fn send_error_to_channel(state: &VoiceState, channel_id: u32, error: &str) {
+    let message = WsServerMessage::Error(error.to_string());
+    send_to_channel(state, channel_id, message);
+}
+
+/// Send a lifecycle event to each analytics subscriber as one JSON line. Fire-and-forget, like
+/// pushes to bots.
+fn emit_analytics(state: &VoiceState, call_id: &str, event: AnalyticsEventKind) {
+    if state.analytics_subscribers.is_empty() {
+        return;
+    }
+    let event = AnalyticsEvent {
+        call_id: call_id.to_string(),
+        timestamp: current_timestamp().unwrap_or(0),
+        event,
+    };
+    let mut line = serde_json::to_vec(&event).unwrap_or_default();
+    line.push(b'\n');
+    for subscriber in &state.analytics_subscribers {
+        if let Err(e) = Request::to(subscriber).body(line.clone()).send() {
+            println!("Failed to send analytics event to {}: {:?}", subscriber, e);
+        }
+    }
+}
+
+/// Deliver to a WebSocket, or to the process behind a bot's virtual channel. Bots only get text
+/// messages; they leave through `bot_leave` rather than a close frame.
+fn push_to_channel(state: &VoiceState, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
+    let Some(bot) = state.bot_channels.get(&channel_id) else {
+        #[cfg(test)]
+        tests::record_push(channel_id, &message_type, &blob.bytes);
+        send_ws_push(channel_id, message_type, blob);
+        return;
+    };
+    if matches!(message_type, WsMessageType::Text) {
+        if let Err(e) = Request::to(bot).body(blob.bytes).send() {
+            println!("Failed to push to bot {} on channel {}: {:?}", bot, channel_id, e);
+        }
+    }
+}
+
+fn disconnect_all_call_channels(state: &VoiceState, call_id: &str, reason: &CallEndReason) {
+    if let Some(channels) = state.call_channels.get(call_id) {
+        println!("Disconnecting {} WebSocket channels for call {}", channels.len(), call_id);
+
+        // First send CallEnded message to all participants
         // We send this first so clients can show the "Call Ended" screen
         for &channel_id in channels {
             println!("Sending CallEnded to channel {}", channel_id);
-            send_to_channel(channel_id, WsServerMessage::CallEnded);
+            send_to_channel(state, channel_id, WsServerMessage::CallEnded { reason: reason.clone() });
         }
 
         // Then send CloseConnection message to tell clients to close their WebSocket
         // Send these as a separate pass to ensure CallEnded is queued first
         for &channel_id in channels {
             println!("Sending CloseConnection to channel {}", channel_id);
-            send_to_channel(channel_id, WsServerMessage::CloseConnection);
+            send_to_channel(state, channel_id, WsServerMessage::CloseConnection);
+        }
+
+        // Finally close the sockets ourselves so clients and proxies see a clean closure rather
+        // than a half-open socket. The platform reports each close back to `websocket`, where
+        // `handle_disconnect` drops the channel's connection mappings.
+        for &channel_id in channels {
+            close_channel(state, channel_id, WS_CLOSE_NORMAL, "Call ended");
         }
     }
 }
 
+/// Send a WebSocket close frame. The payload follows RFC 6455: a big-endian status code then a UTF-8 reason.
+fn close_channel(state: &VoiceState, channel_id: u32, code: u16, reason: &str) {
+    println!("Closing channel {} with code {}: {}", channel_id, code, reason);
+    let mut bytes = code.to_be_bytes().to_vec();
+    bytes.extend_from_slice(reason.as_bytes());
+    let blob = LazyLoadBlob {
+        mime: None,
+        bytes,
+    };
+    push_to_channel(state, channel_id, WsMessageType::Close, blob);
+}
+
 fn base64_to_bytes(base64_str: &str) -> Vec<u8> {
     general_purpose::STANDARD.decode(base64_str).unwrap_or_default()
 }
@@ -1206,3 +4124,465 @@ fn base64_to_bytes(base64_str: &str) -> Vec<u8> {
 fn bytes_to_base64(bytes: &[u8]) -> String {
     general_purpose::STANDARD.encode(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{CodecFactory, OpusDecode, OpusEncode};
+    use std::cell::RefCell;
+
+    thread_local! {
+        // Everything pushed to a WebSocket on this test's thread: (channel, is a close frame, bytes)
+        static PUSHES: RefCell<Vec<(u32, bool, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(super) fn record_push(channel_id: u32, message_type: &WsMessageType, bytes: &[u8]) {
+        let is_close = matches!(message_type, WsMessageType::Close);
+        PUSHES.with(|pushes| pushes.borrow_mut().push((channel_id, is_close, bytes.to_vec())));
+    }
+
+    /// What was pushed to `channel_id` so far: each text message's JSON, or "close" for a close frame.
+    fn pushed_to(channel_id: u32) -> Vec<String> {
+        PUSHES.with(|pushes| {
+            pushes.borrow().iter()
+                .filter(|(id, _, _)| *id == channel_id)
+                .map(|(_, is_close, bytes)| {
+                    if *is_close { "close".to_string() } else { String::from_utf8_lossy(bytes).into_owned() }
+                })
+                .collect()
+        })
+    }
+
+    /// A fresh call as `create_call` would leave it, hosted on our node.
+    fn new_call(default_role: Role) -> Call {
+        Call {
+            id: format!("{}-alpha-beta-gamma", our().node),
+            participants: HashMap::new(),
+            chat_history: Vec::new(),
+            created_at: current_timestamp().unwrap_or(0) / 1000,
+            default_role,
+            default_role_node: None,
+            default_role_browser: None,
+            creator_id: None,
+            host_id: None,
+            reconnect_window_secs: DEFAULT_RECONNECT_WINDOW_SECS,
+            next_seat: 0,
+            push_to_talk: false,
+            speakers_can_mute: false,
+            host_controls_mute: false,
+            persistent: false,
+            max_participants: None,
+            hide_node_ids: false,
+            reserved_slots: HashMap::new(),
+            floor_holder: None,
+            audio_config: AudioConfig::default(),
+            max_chat_history: DEFAULT_MAX_CHAT_HISTORY,
+            custom_roles: HashMap::new(),
+            pinned_speakers: HashSet::new(),
+            audit_log: Vec::new(),
+            idle_speaker_timeout_secs: None,
+            screen_sharers: Vec::new(),
+            max_screen_shares: 1,
+            max_duration_secs: None,
+            pinned_message: None,
+            clock_origin_ms: current_timestamp().unwrap_or(0),
+            mix_clock_samples: None,
+            ending_soon_warnings_sent: 0,
+            last_silence_advisory: None,
+            bytes_sent: HashMap::new(),
+            playout_stats: HashMap::new(),
+            branding: CallBranding::default(),
+        }
+    }
+
+    /// A state holding a single call, returned with its id.
+    fn state_with_call(default_role: Role) -> (VoiceState, String) {
+        let call = new_call(default_role);
+        let call_id = call.id.clone();
+        let mut state = VoiceState {
+            word_dictionary: vec!["apple".to_string(), "banana".to_string(), "cherry".to_string()],
+            ..VoiceState::default()
+        };
+        state.used_pleb_names.insert(call_id.clone(), Vec::new());
+        state.calls.insert(call_id.clone(), call);
+        (state, call_id)
+    }
+
+    /// Handle a client message, keeping whatever it queued instead of sending it.
+    fn dispatch(state: &mut VoiceState, channel_id: u32, msg: WsClientMessage) -> Outbox {
+        let mut outbox = Outbox::default();
+        dispatch_client_message(state, &mut outbox, channel_id, msg);
+        outbox
+    }
+
+    /// Messages queued for one channel specifically, leaving out broadcasts.
+    fn sent_to(outbox: &Outbox, channel_id: u32) -> Vec<&WsServerMessage> {
+        outbox.messages.iter()
+            .filter(|(recipient, _)| matches!(recipient, Recipient::Channel(id) if *id == channel_id))
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    /// Messages queued for everyone in a call.
+    fn broadcasts(outbox: &Outbox) -> Vec<&WsServerMessage> {
+        outbox.messages.iter()
+            .filter(|(recipient, _)| matches!(recipient, Recipient::Call(_)))
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    fn join_message(call_id: &str, auth_token: Option<String>) -> WsClientMessage {
+        WsClientMessage::JoinCall {
+            call_id: call_id.to_string(),
+            auth_token,
+            display_name: None,
+            settings: None,
+            avatar_url: None,
+            resume_token: None,
+            capabilities: None,
+            batch_frames: None,
+            repacketize: None,
+            last_seen_message_id: None,
+        }
+    }
+
+    /// Join from a browser on `channel_id`, returning the new participant's id.
+    fn join(state: &mut VoiceState, channel_id: u32, call_id: &str) -> String {
+        dispatch(state, channel_id, join_message(call_id, None));
+        state.connections[&channel_id].clone()
+    }
+
+    fn audio_frame() -> WsClientMessage {
+        WsClientMessage::AudioData {
+            data: bytes_to_base64(&[0; 4]),
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+            sequence: None,
+            timestamp: None,
+            stream: None,
+        }
+    }
+
+    /// Codecs that can never be created, as when libopus fails to initialise.
+    struct NoCodecs;
+
+    impl CodecFactory for NoCodecs {
+        fn decoder(&self, _sample_rate: u32) -> Result<Box<dyn OpusDecode>, String> {
+            Err("no decoder".to_string())
+        }
+
+        fn encoder(&self, _sample_rate: u32, _stereo: bool) -> Result<Box<dyn OpusEncode>, String> {
+            Err("no encoder".to_string())
+        }
+    }
+
+    #[test]
+    fn audio_from_a_listener_is_dropped_before_the_processor() {
+        let (mut state, call_id) = state_with_call(Role::Listener);
+        join(&mut state, 1, &call_id);
+        join(&mut state, 2, &call_id);
+
+        let outbox = dispatch(&mut state, 2, audio_frame());
+        assert!(matches!(sent_to(&outbox, 2)[..], [WsServerMessage::AudioPermissionDenied]));
+        let processor = state.audio_processors[&call_id].clone();
+        assert_eq!(lock_processor(&processor).stats().frames_decoded, 0);
+
+        // Only the first dropped frame is reported
+        let outbox = dispatch(&mut state, 2, audio_frame());
+        assert!(sent_to(&outbox, 2).is_empty());
+    }
+
+    #[test]
+    fn a_failed_processor_registration_is_reported_to_the_speaker() {
+        let (mut state, call_id) = state_with_call(Role::Listener);
+        join(&mut state, 1, &call_id);
+        dispatch(&mut state, 1, WsClientMessage::Mute(false));
+
+        // The call's processor was lost and its replacement can't make codecs
+        let processor = AudioProcessor::with_codecs(AudioConfig::default(), Box::new(NoCodecs));
+        state.audio_processors.insert(call_id.clone(), Arc::new(Mutex::new(processor)));
+
+        let outbox = dispatch(&mut state, 1, audio_frame());
+        assert!(matches!(
+            sent_to(&outbox, 1)[..],
+            [WsServerMessage::Error(error)] if error.starts_with("Audio setup failed")
+        ));
+    }
+
+    /// Give a call a processor on the passthrough codecs, before anyone joins it.
+    fn use_passthrough_codecs(state: &mut VoiceState, call_id: &str) {
+        let config = state.calls[call_id].audio_config.clone();
+        let processor = AudioProcessor::with_codecs(config, Box::new(crate::codec::PcmPassthrough));
+        state.audio_processors.insert(call_id.to_string(), Arc::new(Mutex::new(processor)));
+    }
+
+    /// One frame of raw PCM at a constant level, as the passthrough codecs expect.
+    fn pcm_frame(level: f32) -> Vec<u8> {
+        (0..FRAME_SIZE).flat_map(|_| ((level * 32767.0) as i16).to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn every_promotion_starts_from_clean_codec_state() {
+        let (mut state, call_id) = state_with_call(Role::Listener);
+        use_passthrough_codecs(&mut state, &call_id);
+        join(&mut state, 1, &call_id);
+        let listener = join(&mut state, 2, &call_id);
+        let processor = state.audio_processors[&call_id].clone();
+        let probe = pcm_frame(0.2);
+
+        let mut first_frames = Vec::new();
+        for _ in 0..3 {
+            dispatch(&mut state, 1, WsClientMessage::UpdateRole { target_id: listener.clone(), new_role: Role::Speaker });
+            first_frames.push(lock_processor(&processor).decode_audio(&listener, DEFAULT_STREAM, &probe).unwrap());
+
+            // A loud burst leaves the limiter and filters holding state from this stint
+            dispatch(&mut state, 2, WsClientMessage::Mute(false));
+            for _ in 0..5 {
+                let frame = WsClientMessage::AudioData {
+                    data: bytes_to_base64(&pcm_frame(1.0)),
+                    sample_rate: SAMPLE_RATE,
+                    channels: 1,
+                    sequence: None,
+                    timestamp: None,
+                    stream: None,
+                };
+                dispatch(&mut state, 2, frame);
+            }
+            dispatch(&mut state, 1, WsClientMessage::UpdateRole { target_id: listener.clone(), new_role: Role::Listener });
+        }
+
+        assert!(first_frames.iter().all(|frame| *frame == first_frames[0]));
+    }
+
+    #[test]
+    fn clearing_an_avatar_forgets_it_and_tells_everyone() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        let participant_id = join(&mut state, 1, &call_id);
+        let avatar_url = |state: &VoiceState| state.calls[&call_id].participants[&participant_id].avatar_url.clone();
+
+        for cleared in [None, Some("   ".to_string())] {
+            dispatch(&mut state, 1, WsClientMessage::UpdateAvatar { avatar_url: Some("https://example.com/me.png".to_string()) });
+            assert_eq!(avatar_url(&state).as_deref(), Some("https://example.com/me.png"));
+
+            let outbox = dispatch(&mut state, 1, WsClientMessage::UpdateAvatar { avatar_url: cleared });
+            assert_eq!(avatar_url(&state), None);
+            assert!(matches!(
+                broadcasts(&outbox)[..],
+                [WsServerMessage::AvatarUpdated { participant_id: id, avatar_url: None }] if *id == participant_id
+            ));
+            // Late joiners and resyncs see it gone too
+            let info = state.calls[&call_id].participants[&participant_id].to_info(true);
+            assert_eq!(info.avatar_url, None);
+        }
+    }
+
+    /// Whether the channel was told the call ended, then to close, and was then closed, in that order.
+    fn saw_call_end(channel_id: u32, reason: &str) -> bool {
+        let pushed = pushed_to(channel_id);
+        let ended = pushed.iter().position(|json| json.starts_with(&format!(r#"{{"CallEnded":{{"reason":"{}"}}"#, reason)));
+        let told_to_close = pushed.iter().position(|json| json == r#""CloseConnection""#);
+        let closed = pushed.iter().position(|json| json == "close");
+        matches!((ended, told_to_close, closed), (Some(a), Some(b), Some(c)) if a < b && b < c)
+    }
+
+    fn assert_purged(state: &VoiceState, call_id: &str) {
+        assert!(!state.calls.contains_key(call_id));
+        assert!(state.connections.is_empty());
+        assert!(state.participant_channels.is_empty());
+        assert!(!state.call_channels.contains_key(call_id));
+        assert!(!state.audio_processors.contains_key(call_id));
+        assert!(state.participant_output_sequences.is_empty());
+    }
+
+    #[test]
+    fn a_host_dropping_tells_everyone_before_the_call_is_purged() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        state.calls.get_mut(&call_id).unwrap().reconnect_window_secs = 0;
+        for channel_id in 1..=3 {
+            join(&mut state, channel_id, &call_id);
+        }
+
+        handle_disconnect(&mut state, 1);
+
+        // The channels are only known through the call's mappings, so reaching them at all means the
+        // call hadn't been purged yet
+        assert!(saw_call_end(2, "HostLeft"));
+        assert!(saw_call_end(3, "HostLeft"));
+        assert_purged(&state, &call_id);
+    }
+
+    #[test]
+    fn a_host_leaving_tells_everyone_before_the_call_is_purged() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        for channel_id in 1..=3 {
+            join(&mut state, channel_id, &call_id);
+        }
+
+        dispatch(&mut state, 1, WsClientMessage::Leave);
+
+        assert!(saw_call_end(2, "HostLeft"));
+        assert!(saw_call_end(3, "HostLeft"));
+        assert_eq!(pushed_to(1).last().map(String::as_str), Some("close"));
+        assert_purged(&state, &call_id);
+    }
+
+    #[test]
+    fn leaving_twice_is_refused_and_the_close_after_is_a_no_op() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        join(&mut state, 1, &call_id);
+        let leaver = join(&mut state, 2, &call_id);
+        join(&mut state, 3, &call_id);
+
+        dispatch(&mut state, 2, WsClientMessage::Leave);
+        assert!(!state.calls[&call_id].participants.contains_key(&leaver));
+        assert_eq!(pushed_to(2).last().map(String::as_str), Some("close"));
+
+        let outbox = dispatch(&mut state, 2, WsClientMessage::Leave);
+        assert!(matches!(sent_to(&outbox, 2)[..], [WsServerMessage::Error(error)] if error == "Not authenticated"));
+
+        // The platform reports the close we sent; it must not count as a second departure
+        handle_disconnect(&mut state, 2);
+        assert_eq!(state.calls[&call_id].participants.len(), 2);
+        assert_eq!(state.connections.len(), 2);
+        let left_notices = pushed_to(1).iter().filter(|json| json.contains("ParticipantLeft")).count();
+        assert_eq!(left_notices, 1);
+    }
+
+    #[test]
+    fn shutdown_ends_every_call_and_closes_every_channel() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        let mut other = new_call(Role::Speaker);
+        other.id = format!("{}-delta-echo-foxtrot", our().node);
+        let other_id = other.id.clone();
+        state.calls.insert(other_id.clone(), other);
+        join(&mut state, 1, &call_id);
+        join(&mut state, 2, &call_id);
+        join(&mut state, 3, &other_id);
+        join(&mut state, 4, &other_id);
+
+        assert_eq!(end_all_calls(&mut state, CallEndReason::Shutdown), 2);
+
+        for channel_id in 1..=4 {
+            let pushed = pushed_to(channel_id);
+            assert_eq!(pushed.len(), 3, "channel {}: {:?}", channel_id, pushed);
+            assert!(pushed[0].starts_with(r#"{"CallEnded":{"reason":"Shutdown"}"#));
+            assert_eq!(pushed[1], r#""CloseConnection""#);
+            assert_eq!(pushed[2], "close");
+        }
+        assert!(state.calls.is_empty());
+        assert!(state.connections.is_empty());
+        assert!(state.participant_channels.is_empty());
+        assert!(state.call_channels.is_empty());
+        assert!(state.audio_processors.is_empty());
+        assert!(matches!(state.ended_calls[&call_id].reason, CallEndReason::Shutdown));
+    }
+
+    #[test]
+    fn chat_is_stripped_of_control_and_bidi_characters() {
+        assert_eq!(sanitize_chat_content("hi\0 there\u{7}").unwrap(), "hi there");
+        assert_eq!(sanitize_chat_content("line one\n\tline two").unwrap(), "line one\n\tline two");
+        assert_eq!(sanitize_chat_content("\u{1b}[31mred\u{1b}[0m").unwrap(), "[31mred[0m");
+        assert_eq!(sanitize_chat_content("abc\u{202E}fdp.exe\u{2066}").unwrap(), "abcfdp.exe");
+        assert_eq!(sanitize_chat_content("  héllo 👋  ").unwrap(), "héllo 👋");
+    }
+
+    #[test]
+    fn chat_with_nothing_displayable_is_refused() {
+        for content in ["", "   ", "\0\u{8}\u{7f}", "\n\t\r", "\u{202A}\u{202C}"] {
+            assert!(sanitize_chat_content(content).is_err(), "{:?}", content);
+        }
+
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        join(&mut state, 1, &call_id);
+        let outbox = dispatch(&mut state, 1, WsClientMessage::Chat("\0\u{1b}".to_string()));
+        assert!(matches!(sent_to(&outbox, 1)[..], [WsServerMessage::Error(_)]));
+        assert!(broadcasts(&outbox).is_empty());
+
+        dispatch(&mut state, 1, WsClientMessage::Chat("ok\0".to_string()));
+        let history = &state.calls[&call_id].chat_history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "ok");
+    }
+
+    #[test]
+    fn only_one_of_two_tokenless_host_joins_becomes_host() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+
+        let first = dispatch(&mut state, 1, join_message(&call_id, Some("not-a-token".to_string())));
+        let second = dispatch(&mut state, 2, join_message(&call_id, Some("not-a-token".to_string())));
+
+        assert!(matches!(sent_to(&first, 1)[..], [WsServerMessage::JoinSuccess { role: Role::Admin, .. }]));
+        assert!(matches!(
+            sent_to(&second, 2)[..],
+            [WsServerMessage::Error(error)] if error == "Invalid authentication token"
+        ));
+        let call = &state.calls[&call_id];
+        assert_eq!(call.participants.len(), 1);
+        assert_eq!(call.creator_id, call.host_id);
+        assert_eq!(call.creator_id.as_ref(), Some(&state.connections[&1]));
+        assert!(!state.connections.contains_key(&2));
+    }
+
+    #[test]
+    fn a_mapping_left_pointing_at_a_removed_call_is_cleaned_up() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        let participant_id = join(&mut state, 1, &call_id);
+
+        // Force the inconsistency: the call is gone but the channel still maps to its participant
+        state.calls.remove(&call_id);
+        dispatch(&mut state, 1, WsClientMessage::Chat("hello".to_string()));
+
+        assert!(!state.connections.contains_key(&1));
+        assert!(!state.participant_channels.contains_key(&participant_id));
+        assert!(state.call_channels.values().all(|channels| !channels.contains(&1)));
+        assert!(!state.participant_output_sequences.contains_key(&participant_id));
+
+        // Anything else from the channel is refused up front rather than looked up again
+        let outbox = dispatch(&mut state, 1, WsClientMessage::Chat("hello".to_string()));
+        assert!(matches!(sent_to(&outbox, 1)[..], [WsServerMessage::Error(error)] if error == "Not authenticated"));
+    }
+
+    #[test]
+    fn aliases_resolve_however_they_are_typed() {
+        let (mut state, call_id) = state_with_call(Role::Speaker);
+        state.call_aliases.insert("team-standup".to_string(), call_id.clone());
+
+        for typed in ["team-standup", "Team-Standup", "  TEAM-STANDUP\n"] {
+            assert_eq!(resolve_call_id(&state, typed), call_id);
+        }
+        // Anything that isn't an alias is taken as an id, untouched
+        assert_eq!(resolve_call_id(&state, &call_id), call_id);
+        assert_eq!(resolve_call_id(&state, "Other-Call"), "Other-Call");
+    }
+
+    #[test]
+    fn redefining_a_role_switches_speaking_for_everyone_holding_it() {
+        let (mut state, call_id) = state_with_call(Role::Listener);
+        join(&mut state, 1, &call_id);
+        let panelist = join(&mut state, 2, &call_id);
+        let panel = Role::Custom("panel".to_string());
+        let define = |permissions| WsClientMessage::DefineRole { name: "panel".to_string(), permissions };
+        let speaking_enabled = |outbox: &Outbox| -> Vec<bool> {
+            sent_to(outbox, 2).iter()
+                .filter_map(|message| match message {
+                    WsServerMessage::SpeakingEnabled { enabled } => Some(*enabled),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        dispatch(&mut state, 1, define(Permissions::SPEAK.union(Permissions::CHAT)));
+        let outbox = dispatch(&mut state, 1, WsClientMessage::UpdateRole { target_id: panelist.clone(), new_role: panel });
+        assert_eq!(speaking_enabled(&outbox), [true]);
+
+        let outbox = dispatch(&mut state, 1, define(Permissions::CHAT));
+        assert_eq!(speaking_enabled(&outbox), [false]);
+
+        // Redefining without touching speaking says nothing
+        let outbox = dispatch(&mut state, 1, define(Permissions::CHAT.union(Permissions::REACT)));
+        assert!(speaking_enabled(&outbox).is_empty());
+
+        let outbox = dispatch(&mut state, 1, define(Permissions::SPEAK));
+        assert_eq!(speaking_enabled(&outbox), [true]);
+    }
+}
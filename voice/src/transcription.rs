@@ -0,0 +1,85 @@
+// Live captions. The audio processor hands every decoded speech frame to a `TranscriptionSink`,
+// which can be anything from a local speech-to-text model to a bridge to an external service.
+
+const VOICE_THRESHOLD: f32 = 0.01; // Peak above which a frame counts as speech
+const SEGMENT_END_SILENT_FRAMES: u32 = 15; // ~300ms of silence closes a segment
+const FRAME_DURATION_SECS: f32 = 0.02;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caption {
+    pub participant_id: String,
+    pub text: String,
+    pub is_final: bool, // Interim captions may be replaced by a later one for the same speaker
+}
+
+pub trait TranscriptionSink: Send {
+    /// One 20ms mono frame at the mixer rate from a speaker, before any mixing gain is applied.
+    fn push_frame(&mut self, participant_id: &str, pcm: &[f32]);
+
+    /// The speaker is gone; finish anything in progress for them.
+    fn end_participant(&mut self, _participant_id: &str) {}
+
+    /// Captions produced since the last call.
+    fn drain_captions(&mut self) -> Vec<Caption>;
+}
+
+/// Placeholder transcriber that only detects speaking segments: an interim caption when someone
+/// starts talking and a final one with the segment's length when they stop.
+#[derive(Debug, Default)]
+pub struct SegmentSink {
+    segments: std::collections::HashMap<String, Segment>,
+    captions: Vec<Caption>,
+}
+
+#[derive(Debug, Default)]
+struct Segment {
+    voiced_frames: u32,
+    silent_frames: u32,
+}
+
+impl SegmentSink {
+    fn finish(&mut self, participant_id: &str) {
+        if let Some(segment) = self.segments.remove(participant_id) {
+            self.captions.push(Caption {
+                participant_id: participant_id.to_string(),
+                text: format!("[spoke for {:.1}s]", segment.voiced_frames as f32 * FRAME_DURATION_SECS),
+                is_final: true,
+            });
+        }
+    }
+}
+
+impl TranscriptionSink for SegmentSink {
+    fn push_frame(&mut self, participant_id: &str, pcm: &[f32]) {
+        let voiced = pcm.iter().any(|sample| sample.abs() >= VOICE_THRESHOLD);
+        match self.segments.get_mut(participant_id) {
+            Some(segment) if voiced => {
+                segment.voiced_frames += 1;
+                segment.silent_frames = 0;
+            }
+            Some(segment) => {
+                segment.silent_frames += 1;
+                if segment.silent_frames >= SEGMENT_END_SILENT_FRAMES {
+                    self.finish(participant_id);
+                }
+            }
+            None if voiced => {
+                self.segments.insert(participant_id.to_string(), Segment { voiced_frames: 1, silent_frames: 0 });
+                self.captions.push(Caption {
+                    participant_id: participant_id.to_string(),
+                    text: "[speaking]".to_string(),
+                    is_final: false,
+                });
+            }
+            None => {}
+        }
+    }
+
+    fn end_participant(&mut self, participant_id: &str) {
+        self.finish(participant_id);
+    }
+
+    fn drain_captions(&mut self) -> Vec<Caption> {
+        std::mem::take(&mut self.captions)
+    }
+}